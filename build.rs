@@ -1,4 +1,3 @@
-use lalrpop;
 
 fn main() {
     lalrpop::Configuration::new().process_dir("./src/").unwrap()
@@ -1,3 +1,5 @@
+use std::fmt;
+
 use derive_getters::Getters;
 use derive_new::new;
 
@@ -5,13 +7,48 @@ use crate::scheme::ast::Value as SchemeValue;
 
 #[derive(Debug, new, Getters, Clone)]
 pub struct Query {
+    /// The modules imported at the start of the query. Included modules are
+    /// merged into the query when it's loaded.
+    imports: Vec<Import>,
+    /// The functions defined at the start of the query, visible in its filters
+    defs: Vec<FuncDef>,
     filters: Vec<Box<Filter>>,
 }
 
+/// import "path" as name; or include "path";
+#[derive(Debug, new, Getters, Clone)]
+pub struct Import {
+    path: String,
+    /// The functions of an imported module are called as `name::func`, an
+    /// included module doesn't have a name
+    alias: Option<String>,
+    /// The module, once loaded by a `ModuleLoader`
+    #[new(default)]
+    module: Option<Box<Query>>,
+}
+
+impl Import {
+    pub fn with_module(self, module: Query) -> Self {
+        Import {
+            module: Some(Box::new(module)),
+            ..self
+        }
+    }
+}
+
+/// def name(params): body;
+#[derive(Debug, new, Getters, Clone)]
+pub struct FuncDef {
+    name: String,
+    /// The parameters are filters, evaluated where they are used in the body
+    params: Vec<String>,
+    body: Query,
+}
+
 #[derive(Debug, Clone)]
 pub enum Filter {
     Identity,
-    Key(String),
+    Key(Key),
     Tail(String),
     Head(String),
     Index(i64),
@@ -19,16 +56,299 @@ pub enum Filter {
     /// x | filter1, filter2, ... | y
     Branch(Vec<Box<Filter>>),
     ListIter,
+    /// .., the value and everything nested in it
+    Recurse,
     FuncCall {
         func: String,
         args: Vec<Box<Expr>>,
     },
     SubQuery(Box<Query>),
     Expr(Box<Expr>),
+    /// source as pattern | body
+    Bind {
+        source: Box<Query>,
+        pattern: Pattern,
+        body: Box<Query>,
+    },
+    /// if cond then then else otherwise end, `elif` is an `If` in `otherwise`
+    If {
+        cond: Box<Query>,
+        then: Box<Query>,
+        otherwise: Option<Box<Query>>,
+    },
+    /// match 'pattern | body, see `scheme::pattern`
+    Match {
+        pattern: Box<SchemeValue>,
+        body: Box<Query>,
+    },
+    /// lhs op rhs, both sides get the same input
+    Binary {
+        op: BinaryOp,
+        lhs: Box<Query>,
+        rhs: Box<Query>,
+    },
+    /// not query
+    Not(Box<Query>),
+    /// (element1 element2 ...), a list of the outputs of the elements
+    List(Vec<Box<Expr>>),
+    /// #(element1 element2 ...)
+    Vector(Vec<Box<Expr>>),
+    /// `template
+    Quasiquote(Box<Template>),
+}
+
+/// What `;key` looks for in a property list or an association list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Key {
+    /// ;name
+    Symbol(String),
+    /// ;:name or ;#:name, matching the keyword whatever its style
+    Keyword(String),
+}
+
+impl Key {
+    pub fn matches(&self, value: &SchemeValue) -> bool {
+        match (self, value) {
+            (Key::Symbol(key), SchemeValue::Symbol(name))
+            | (Key::Keyword(key), SchemeValue::Keyword(name, _)) => key == name,
+            _ => false,
+        }
+    }
+}
+
+/// The infix operators of the query language
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Add,
+    Subtract,
+    Multiply,
+    /// Gives an integer when both sides are integers and the division is
+    /// exact, a float otherwise
+    Divide,
+    /// The remainder of the truncated division, with the sign of the left side
+    Remainder,
+    /// Doesn't evaluate its right side when the left side is false
+    And,
+    /// Doesn't evaluate its right side when the left side is true
+    Or,
 }
 
 #[derive(Debug, Clone)]
 pub enum Expr {
     Filter(Box<Filter>),
     Value(Box<SchemeValue>),
+    /// $name
+    Variable(String),
+}
+
+/// The value built by a quasiquote, with the outputs of queries at the
+/// unquoted positions
+#[derive(Debug, Clone)]
+pub enum Template {
+    Value(Box<SchemeValue>),
+    Quote(Box<Template>),
+    List(Vec<Template>),
+    /// (a b . tail), one value per output of the tail
+    Dotted(Vec<Template>, Box<Template>),
+    Vector(Vec<Template>),
+    /// ,(query), replaced by the outputs of the query
+    Unquote(Box<Query>),
+    /// ,@(query), replaced by the elements of the lists output by the query
+    UnquoteSplicing(Box<Query>),
+}
+
+/// What the value bound by `as` is destructured with
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// $name
+    Variable(String),
+    /// ($first $second $rest ...), the last variable takes the rest of the
+    /// list when followed by `...`
+    List {
+        elements: Vec<Pattern>,
+        rest: Option<String>,
+    },
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for import in &self.imports {
+            write!(f, "{} ", import)?;
+        }
+        for def in &self.defs {
+            write!(f, "{} ", def)?;
+        }
+        let filters = self
+            .filters
+            .iter()
+            .map(|filter| filter.to_string())
+            .collect::<Vec<_>>()
+            .join(" | ");
+        write!(f, "{}", filters)
+    }
+}
+
+impl fmt::Display for Import {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = SchemeValue::String(self.path.clone());
+        match &self.alias {
+            Some(alias) => write!(f, "import {} as {};", path, alias),
+            None => write!(f, "include {};", path),
+        }
+    }
+}
+
+impl fmt::Display for FuncDef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "def {}", self.name)?;
+        if !self.params.is_empty() {
+            write!(f, "({})", self.params.join(" "))?;
+        }
+        write!(f, ": {};", self.body)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let to_string = match self {
+            Filter::Identity => ";".to_owned(),
+            Filter::Key(key) => format!(";{}", key),
+            Filter::Tail(key) => format!(";[{}", key),
+            Filter::Head(key) => format!(";{}]", key),
+            Filter::Index(i) => format!(";[{}]", i),
+            Filter::Slice(start, end) => format!(
+                ";[{}:{}]",
+                start.map(|s| s.to_string()).unwrap_or_default(),
+                end.map(|e| e.to_string()).unwrap_or_default()
+            ),
+            Filter::Branch(filters) => filters
+                .iter()
+                .map(|filter| filter.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            Filter::ListIter => ";()".to_owned(),
+            Filter::Recurse => "..".to_owned(),
+            Filter::FuncCall { func, args } => std::iter::once(func.clone())
+                .chain(args.iter().map(|arg| arg.to_string()))
+                .collect::<Vec<_>>()
+                .join(" "),
+            Filter::SubQuery(query) => format!(";({})", query),
+            Filter::Expr(expr) => expr.to_string(),
+            Filter::Bind {
+                source,
+                pattern,
+                body,
+            } => format!("{} as {} | {}", source, pattern, body),
+            Filter::If {
+                cond,
+                then,
+                otherwise,
+            } => match otherwise {
+                Some(otherwise) => format!("if {} then {} else {} end", cond, then, otherwise),
+                None => format!("if {} then {} end", cond, then),
+            },
+            Filter::Match { pattern, body } if body.filters().is_empty() => {
+                format!("match {}", pattern)
+            }
+            Filter::Match { pattern, body } => format!("match {} | {}", pattern, body),
+            Filter::Binary { op, lhs, rhs } => format!("{} {} {}", lhs, op, rhs),
+            Filter::Not(query) => format!("not {}", query),
+            Filter::List(elements) => format!("({})", join(elements)),
+            Filter::Vector(elements) => format!("#({})", join(elements)),
+            Filter::Quasiquote(template) => format!("`{}", template),
+        };
+        write!(f, "{}", to_string)
+    }
+}
+
+fn join(exprs: &[Box<Expr>]) -> String {
+    exprs
+        .iter()
+        .map(|expr| expr.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Key::Symbol(name) => write!(f, "{}", name),
+            Key::Keyword(name) => write!(f, ":{}", name),
+        }
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self {
+            BinaryOp::Equal => "==",
+            BinaryOp::NotEqual => "!=",
+            BinaryOp::Less => "<",
+            BinaryOp::LessEqual => "<=",
+            BinaryOp::Greater => ">",
+            BinaryOp::GreaterEqual => ">=",
+            BinaryOp::Add => "+",
+            BinaryOp::Subtract => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Remainder => "%",
+            BinaryOp::And => "and",
+            BinaryOp::Or => "or",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Filter(filter) => write!(f, "{}", filter),
+            Expr::Value(value) => write!(f, "{}", value),
+            Expr::Variable(name) => write!(f, "${}", name),
+        }
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |templates: &[Template]| {
+            templates
+                .iter()
+                .map(|template| template.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        match self {
+            Template::Value(value) => write!(f, "{}", value),
+            Template::Quote(template) => write!(f, "'{}", template),
+            Template::List(templates) => write!(f, "({})", join(templates)),
+            Template::Dotted(templates, tail) => write!(f, "({} . {})", join(templates), tail),
+            Template::Vector(templates) => write!(f, "#({})", join(templates)),
+            Template::Unquote(query) => write!(f, ",({})", query),
+            Template::UnquoteSplicing(query) => write!(f, ",@({})", query),
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pattern::Variable(name) => write!(f, "${}", name),
+            Pattern::List { elements, rest } => {
+                let elements = elements
+                    .iter()
+                    .map(|pattern| pattern.to_string())
+                    .chain(rest.iter().map(|rest| format!("${} ...", rest)))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                write!(f, "({})", elements)
+            }
+        }
+    }
 }
@@ -0,0 +1,73 @@
+use std::{fmt::Display, ops::Range};
+
+use ariadne::{Config, IndexType, Label, Report, ReportKind, Source};
+use lalrpop_util::ParseError;
+
+/// An error that knows where it happened in the source
+pub trait SpannedError: Display {
+    fn span(&self) -> Range<usize>;
+
+    /// What to underline the span with
+    fn label(&self) -> String;
+}
+
+/// Renders a parse error, quoting the offending line of `source` and
+/// underlining the token at fault
+pub fn parse_error_report<T: Display, E: SpannedError>(
+    source_name: &str,
+    source: &str,
+    error: &ParseError<usize, T, E>,
+    color: bool,
+) -> String {
+    let (span, message, label) = match error {
+        ParseError::InvalidToken { location } => (
+            *location..*location + 1,
+            "invalid token".to_owned(),
+            "this token is not valid here".to_owned(),
+        ),
+        ParseError::UnrecognizedEof { location, expected } => (
+            *location..*location,
+            "unexpected end of input".to_owned(),
+            expected_message(expected),
+        ),
+        ParseError::UnrecognizedToken {
+            token: (start, token, end),
+            expected,
+        } => (
+            *start..*end,
+            format!("unexpected token {}", token),
+            expected_message(expected),
+        ),
+        ParseError::ExtraToken {
+            token: (start, token, end),
+        } => (
+            *start..*end,
+            format!("extra token {}", token),
+            "expected nothing after this".to_owned(),
+        ),
+        ParseError::User { error } => (error.span(), error.to_string(), error.label()),
+    };
+
+    let mut report = vec![];
+    Report::build(ReportKind::Error, source_name, span.start)
+        .with_config(
+            Config::default()
+                .with_color(color)
+                .with_index_type(IndexType::Byte),
+        )
+        .with_message(message)
+        .with_label(Label::new((source_name, span)).with_message(label))
+        .finish()
+        .write((source_name, Source::from(source)), &mut report)
+        .expect("writing to a Vec can't fail");
+
+    String::from_utf8_lossy(&report).into_owned()
+}
+
+fn expected_message(expected: &[String]) -> String {
+    match expected {
+        [] => "unexpected here".to_owned(),
+        [one] => format!("expected {}", one),
+        [many @ .., last] => format!("expected one of {} or {}", many.join(", "), last),
+    }
+}
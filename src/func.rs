@@ -1,39 +1,192 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, rc::Rc};
 
-use crate::{ast::Expr, scheme::ast::Value as SchemeValue};
-use once_cell::sync::Lazy;
+use crate::{
+    ast::Expr,
+    query::QueryError,
+    scheme::{ast::Value as SchemeValue, lisp},
+};
 
 #[derive(Debug, Clone)]
 pub enum FuncError {
     WrongType(),
+    UnknownFunc(String),
+    EmptyList(),
+    WrongArity { expected: Arity, got: usize },
+    /// A function defined in the query failed
+    QueryError(Box<QueryError>),
+    /// Scheme code run with `scm` failed
+    Lisp(String),
 }
 
-type BuiltinFuncType = HashMap<
-    &'static str,
-    fn(Box<SchemeValue>, Vec<Box<SchemeValue>>) -> Result<Option<Box<SchemeValue>>, FuncError>,
->;
+impl std::fmt::Display for FuncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FuncError::WrongType() => write!(f, "wrong argument type"),
+            FuncError::UnknownFunc(name) => write!(f, "unknown function `{}`", name),
+            FuncError::EmptyList() => write!(f, "expected a non-empty list"),
+            FuncError::WrongArity { expected, got } => {
+                write!(f, "expected {}, got {}", expected, got)
+            }
+            FuncError::QueryError(error) => write!(f, "{}", error.message()),
+            FuncError::Lisp(msg) => write!(f, "scheme error: {}", msg),
+        }
+    }
+}
+
+/// The number of arguments a function takes, not counting the value it's
+/// applied to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, count: usize) -> bool {
+        match self {
+            Arity::Exactly(n) => count == *n,
+            Arity::AtLeast(n) => count >= *n,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (prefix, n) = match self {
+            Arity::Exactly(n) => ("", n),
+            Arity::AtLeast(n) => ("at least ", n),
+        };
+        let plural = if *n == 1 { "" } else { "s" };
+        write!(f, "{}{} argument{}", prefix, n, plural)
+    }
+}
+
+pub type FuncResult = Result<Option<Box<SchemeValue>>, FuncError>;
+
+/// A function callable from a query. It gets the registry it was called from
+/// so it can call other functions, the value it's applied to and its
+/// arguments. Returning `None` drops the value.
+pub type FuncType =
+    dyn Fn(&FunctionRegistry, Box<SchemeValue>, Vec<Box<SchemeValue>>) -> FuncResult;
+
+#[derive(Clone)]
+pub struct Function {
+    arity: Arity,
+    /// The argument naming the function this one calls, if any
+    function_arg: Option<usize>,
+    func: Rc<FuncType>,
+}
+
+impl Function {
+    pub fn arity(&self) -> Arity {
+        self.arity
+    }
+
+    pub fn function_arg(&self) -> Option<usize> {
+        self.function_arg
+    }
+}
+
+/// The functions a query can call by name
+#[derive(Clone, Default)]
+pub struct FunctionRegistry {
+    funcs: HashMap<String, Function>,
+}
+
+impl fmt::Debug for FunctionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.funcs.keys()).finish()
+    }
+}
+
+impl FunctionRegistry {
+    /// A registry without any function
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a function, replacing any function with the same name
+    pub fn register<F>(&mut self, name: impl Into<String>, arity: Arity, func: F) -> &mut Self
+    where
+        F: Fn(&FunctionRegistry, Box<SchemeValue>, Vec<Box<SchemeValue>>) -> FuncResult + 'static,
+    {
+        self.funcs.insert(
+            name.into(),
+            Function {
+                arity,
+                function_arg: None,
+                func: Rc::new(func),
+            },
+        );
+        self
+    }
+
+    /// Marks the argument `index` of `name` as the name of a function it
+    /// calls, so a parameter bound to a function is passed there by name
+    /// rather than evaluated
+    pub fn with_function_arg(&mut self, name: &str, index: usize) -> &mut Self {
+        if let Some(function) = self.funcs.get_mut(name) {
+            function.function_arg = Some(index);
+        }
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Function> {
+        self.funcs.get(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.funcs.contains_key(name)
+    }
+
+    /// Calls the function `name` after checking its arity
+    pub fn call(
+        &self,
+        name: &str,
+        value: Box<SchemeValue>,
+        args: Vec<Box<SchemeValue>>,
+    ) -> FuncResult {
+        let function = self
+            .get(name)
+            .ok_or_else(|| FuncError::UnknownFunc(name.to_owned()))?;
+        if !function.arity.accepts(args.len()) {
+            return Err(FuncError::WrongArity {
+                expected: function.arity,
+                got: args.len(),
+            });
+        }
+        (function.func)(self, value, args)
+    }
+
+    /// A registry with the builtin functions
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::new();
+        register_builtins(&mut registry);
+        registry
+    }
+}
 
 macro_rules! car_fns {
     ($map:expr, $($name:literal),+) => {{
         $(
-        $map.insert($name, |value, args| {
+        $map.register($name, Arity::Exactly(0), |_, value, args| {
             let mut v = *value;
             for op in 2..$name.len() {
                 let op = $name.len() - op;
                 match v {
-                    SchemeValue::List(ref l) | SchemeValue::Vector(ref l) => {
-                        if &$name[op..op+1] == "a" {
-                            v = *l.get(0).cloned().unwrap();
-                        } else if &$name[op..op+1] == "d"{
-                            v = SchemeValue::List(
-                                l.into_iter().skip(1).cloned().collect::<Vec<_>>(),
-                            );
-                        } else {
-                            unreachable!()
-                        }
+                    SchemeValue::List(ref l) | SchemeValue::Vector(ref l) if l.is_empty() => {
+                        return Err(FuncError::EmptyList())
                     }
-                    _ => return Err(FuncError::WrongType()),
+                    SchemeValue::Nil => return Err(FuncError::EmptyList()),
+                    _ => {}
                 }
+                v = if &$name[op..op+1] == "a" {
+                    v.car().cloned().ok_or(FuncError::WrongType())?
+                } else if &$name[op..op+1] == "d" {
+                    v.cdr().ok_or(FuncError::WrongType())?
+                } else {
+                    unreachable!()
+                };
             }
             Ok(Some(Box::new(v)))
         });
@@ -42,9 +195,7 @@ macro_rules! car_fns {
     };
 }
 
-pub static BUILTIN_FUNCS: Lazy<BuiltinFuncType> = Lazy::new(|| {
-    let mut map: BuiltinFuncType = HashMap::new();
-
+fn register_builtins(map: &mut FunctionRegistry) {
     car_fns!(
         map, "car", "cdr", "caar", "cadr", "cdar", "cddr", "caaar", "caadr", "cadar", "cdaar",
         "caddr", "cdadr", "cddar", "cdddr", "caaadr", "caaddr", "cadadr", "cdaadr", "cadddr",
@@ -52,86 +203,105 @@ pub static BUILTIN_FUNCS: Lazy<BuiltinFuncType> = Lazy::new(|| {
         "cddaar", "cdddar"
     );
 
-    map.insert("cr", |value, args| Ok(Some(value)));
+    map.register("cr", Arity::Exactly(0), |funcs, value, args| {
+        Ok(Some(value))
+    });
 
-    map.insert("eqv?", |value, args| {
+    map.register("eqv?", Arity::Exactly(1), |funcs, value, args| {
         Ok(Some(Box::new(SchemeValue::Bool(*value == *args[0]))))
     });
 
-    map.insert("=?", |value, args| {
+    map.register("=?", Arity::Exactly(1), |funcs, value, args| {
         Ok(Some(Box::new(SchemeValue::Bool(*value == *args[0]))))
     });
 
-    map.insert(">=?", |value, args| {
+    map.register(">=?", Arity::Exactly(1), |funcs, value, args| {
         Ok(Some(Box::new(SchemeValue::Bool(*value >= *args[0]))))
     });
 
-    map.insert(">?", |value, args| {
+    map.register(">?", Arity::Exactly(1), |funcs, value, args| {
         Ok(Some(Box::new(SchemeValue::Bool(*value > *args[0]))))
     });
 
-    map.insert("<=?", |value, args| {
+    map.register("<=?", Arity::Exactly(1), |funcs, value, args| {
         Ok(Some(Box::new(SchemeValue::Bool(*value <= *args[0]))))
     });
 
-    map.insert("<?", |value, args| {
+    map.register("<?", Arity::Exactly(1), |funcs, value, args| {
         Ok(Some(Box::new(SchemeValue::Bool(*value < *args[0]))))
     });
 
-    map.insert("length", |value, args| match *value {
-        SchemeValue::List(ref l) | SchemeValue::Vector(ref l) => {
-            Ok(Some(Box::new(SchemeValue::Int(l.len() as i64))))
-        }
-        _ => Err(FuncError::WrongType()),
-    });
+    map.register(
+        "length",
+        Arity::Exactly(0),
+        |funcs, value, args| match *value {
+            SchemeValue::List(ref l) | SchemeValue::Vector(ref l) => {
+                Ok(Some(Box::new(SchemeValue::Int(l.len() as i64))))
+            }
+            _ => Err(FuncError::WrongType()),
+        },
+    );
 
-    map.insert("filter", |value, args| match *args[0].clone() {
-        SchemeValue::Symbol(v) => {
-            let f = *BUILTIN_FUNCS.get(v.as_str()).unwrap();
-            let result = f(value.clone(), args[1..].to_vec())?.unwrap();
-            if !matches!(*result, SchemeValue::Bool(false)) {
-                Ok(Some(value))
-            } else {
-                Ok(None)
+    map.register(
+        "filter",
+        Arity::AtLeast(1),
+        |funcs, value, args| match *args[0].clone() {
+            SchemeValue::Symbol(v) => {
+                let result = funcs.call(&v, value.clone(), args[1..].to_vec())?;
+                if result.is_some_and(|result| !matches!(*result, SchemeValue::Bool(false))) {
+                    Ok(Some(value))
+                } else {
+                    Ok(None)
+                }
             }
-        }
-        SchemeValue::Bool(b) => {
-            if b {
-                Ok(Some(value))
-            } else {
-                Ok(None)
+            SchemeValue::Bool(b) => {
+                if b {
+                    Ok(Some(value))
+                } else {
+                    Ok(None)
+                }
             }
-        }
-        _ => Err(FuncError::WrongType()),
-    });
+            _ => Err(FuncError::WrongType()),
+        },
+    );
 
-    map.insert("select", |value, args| match *args[0].clone() {
-        SchemeValue::Symbol(v) => {
-            let f = *BUILTIN_FUNCS.get(v.as_str()).unwrap();
-            let result = f(args[1].clone(), args[2..].to_vec())?.unwrap();
-            if !matches!(*result, SchemeValue::Bool(false)) {
-                Ok(Some(value))
-            } else {
-                Ok(None)
+    map.register(
+        "select",
+        Arity::AtLeast(1),
+        |funcs, value, args| match *args[0].clone() {
+            SchemeValue::Symbol(_) if args.len() < 2 => Err(FuncError::WrongArity {
+                expected: Arity::AtLeast(2),
+                got: args.len(),
+            }),
+            SchemeValue::Symbol(v) => {
+                let result = funcs.call(&v, args[1].clone(), args[2..].to_vec())?;
+                if result.is_some_and(|result| !matches!(*result, SchemeValue::Bool(false))) {
+                    Ok(Some(value))
+                } else {
+                    Ok(None)
+                }
             }
-        }
-        _ => Err(FuncError::WrongType()),
-    });
+            // select ;(;age >= 18)
+            SchemeValue::Bool(b) => Ok(b.then_some(value)),
+            _ => Err(FuncError::WrongType()),
+        },
+    );
 
-    map.insert("map", |value, args| match *args[1].clone() {
-        SchemeValue::Symbol(v) => {
-            let f = *BUILTIN_FUNCS.get(v.as_str()).unwrap();
-            let result = f(args[0].clone(), args[2..].to_vec())?.unwrap();
-            if !matches!(*result, SchemeValue::Bool(false)) {
-                Ok(Some(value))
-            } else {
-                Ok(None)
+    map.register("map", Arity::AtLeast(2), |funcs, value, args| {
+        match *args[1].clone() {
+            SchemeValue::Symbol(v) => {
+                let result = funcs.call(&v, args[0].clone(), args[2..].to_vec())?;
+                if result.is_some_and(|result| !matches!(*result, SchemeValue::Bool(false))) {
+                    Ok(Some(value))
+                } else {
+                    Ok(None)
+                }
             }
+            _ => Err(FuncError::WrongType()),
         }
-        _ => Err(FuncError::WrongType()),
     });
 
-    map.insert("print", |value, args| {
+    map.register("print", Arity::AtLeast(0), |funcs, value, args| {
         println!(
             "; is '{} & args is '({})",
             value,
@@ -143,14 +313,100 @@ pub static BUILTIN_FUNCS: Lazy<BuiltinFuncType> = Lazy::new(|| {
         Ok(Some(value))
     });
 
-    map.insert("inspect", |value, args| match *args[0].clone() {
-        SchemeValue::Symbol(v) => {
-            let f = *BUILTIN_FUNCS.get(v.as_str()).unwrap();
-            let result = f(value.clone(), args[1..].to_vec())?.unwrap();
-            Ok(Some(value))
+    map.register(
+        "inspect",
+        Arity::AtLeast(1),
+        |funcs, value, args| match *args[0].clone() {
+            SchemeValue::Symbol(v) => {
+                funcs.call(&v, value.clone(), args[1..].to_vec())?;
+                Ok(Some(value))
+            }
+            _ => Err(FuncError::WrongType()),
+        },
+    );
+
+    map.register("walk", Arity::AtLeast(1), |funcs, value, args| {
+        match args[0].as_ref() {
+            SchemeValue::Symbol(name) => walk(funcs, name, *value, &args[1..]),
+            _ => Err(FuncError::WrongType()),
         }
-        _ => Err(FuncError::WrongType()),
     });
 
-    map
-});
+    for name in ["filter", "select", "inspect", "walk"] {
+        map.with_function_arg(name, 0);
+    }
+    map.with_function_arg("map", 1);
+
+    map.register("descendants", Arity::Exactly(0), |funcs, value, args| {
+        let descendants = value
+            .recurse()
+            .into_iter()
+            .skip(1)
+            .map(|v| Box::new(v.clone()))
+            .collect();
+        Ok(Some(Box::new(SchemeValue::List(descendants))))
+    });
+
+    // `scm '(lambda (x) ...)` applies the lambda to the value
+    for name in ["scm", "lambda"] {
+        map.register(name, Arity::AtLeast(1), |funcs, value, args| {
+            let expr = match args[0].as_ref() {
+                SchemeValue::Quote(expr) => expr,
+                _ => return Err(FuncError::WrongType()),
+            };
+            lisp::eval_on(expr, &value, &args[1..])
+                .map(|result| Some(Box::new(result)))
+        });
+    }
+}
+
+/// Rebuilds the value from the leaves up, calling `name` on every value
+/// nested in it and then on the value itself. The values dropped by the
+/// function are removed from their parent.
+fn walk(
+    funcs: &FunctionRegistry,
+    name: &str,
+    value: SchemeValue,
+    args: &[Box<SchemeValue>],
+) -> FuncResult {
+    let walk_all = |values: Vec<Box<SchemeValue>>| {
+        values
+            .into_iter()
+            .filter_map(|v| walk(funcs, name, *v, args).transpose())
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let walk_quoted = |v: Box<SchemeValue>, quote: fn(Box<SchemeValue>) -> SchemeValue| {
+        walk(funcs, name, *v, args).map(|v| v.map(quote))
+    };
+
+    let value = match value {
+        SchemeValue::List(l) => SchemeValue::List(walk_all(l)?),
+        SchemeValue::Vector(l) => SchemeValue::Vector(walk_all(l)?),
+        SchemeValue::Pair(l, tail) => {
+            let values = walk_all(l)?;
+            match walk(funcs, name, *tail, args)? {
+                Some(tail) => SchemeValue::dotted(values, tail),
+                None => SchemeValue::List(values),
+            }
+        }
+        SchemeValue::Quote(v) => match walk_quoted(v, SchemeValue::Quote)? {
+            Some(value) => value,
+            None => return Ok(None),
+        },
+        SchemeValue::Quasiquote(v) => match walk_quoted(v, SchemeValue::Quasiquote)? {
+            Some(value) => value,
+            None => return Ok(None),
+        },
+        SchemeValue::Unquote(v) => match walk_quoted(v, SchemeValue::Unquote)? {
+            Some(value) => value,
+            None => return Ok(None),
+        },
+        SchemeValue::UnquoteSplicing(v) => match walk_quoted(v, SchemeValue::UnquoteSplicing)? {
+            Some(value) => value,
+            None => return Ok(None),
+        },
+        value => value,
+    };
+
+    funcs.call(name, Box::new(value), args.to_vec())
+}
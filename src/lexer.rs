@@ -1,17 +1,37 @@
+use std::ops::Range;
+
 use logos::{Logos, SpannedIter};
 
+use crate::diagnostic::SpannedError;
+use crate::scheme::token::TokenError;
 use crate::token::Token;
 
 pub type Spanned<Tok, Loc, Error> = Result<(Loc, Tok, Loc), Error>;
 
 #[derive(Debug)]
 pub enum LexicalError {
-    InvalidToken,
+    InvalidToken(Range<usize>, TokenError),
 }
 
 impl std::fmt::Display for LexicalError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Invalid token")
+        match self {
+            LexicalError::InvalidToken(..) => write!(f, "invalid token"),
+        }
+    }
+}
+
+impl SpannedError for LexicalError {
+    fn span(&self) -> Range<usize> {
+        match self {
+            LexicalError::InvalidToken(span, _) => span.clone(),
+        }
+    }
+
+    fn label(&self) -> String {
+        match self {
+            LexicalError::InvalidToken(_, error) => error.to_string(),
+        }
     }
 }
 
@@ -36,8 +56,9 @@ impl<'input> Iterator for Lexer<'input> {
         self.token_stream.next().map(|(token, span)| {
             match token {
                 // an invalid token was met
-                Ok(Token::Error) => Err(LexicalError::InvalidToken),
-                _ => Ok((span.start, token.unwrap(), span.end)),
+                Ok(Token::Error) => Err(LexicalError::InvalidToken(span, TokenError::default())),
+                Err(error) => Err(LexicalError::InvalidToken(span, error)),
+                Ok(token) => Ok((span.start, token, span.end)),
             }
         })
     }
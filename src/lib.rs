@@ -0,0 +1,74 @@
+#![allow(unused)]
+#![allow(clippy::vec_box)]
+
+//! The lsq query engine: compile a query, parse some Scheme and run the
+//! query on it.
+//!
+//! ```
+//! let query = lsq::compile(";() | ;name").unwrap();
+//! let input = lsq::parse_scheme(r#"((name "Mathis") (name "Jean"))"#).unwrap();
+//! let results = lsq::handle_query(query, input).unwrap();
+//! let options = lsq::ReprOptions::new(false, 80, false);
+//! assert_eq!(
+//!     lsq::format_values(&results, lsq::FormatOptions::Compact, &options),
+//!     "\"Mathis\"\n\"Jean\""
+//! );
+//! ```
+
+pub mod ast;
+pub mod diagnostic;
+pub mod func;
+pub mod lexer;
+pub mod module;
+pub mod query;
+pub mod scheme;
+pub mod token;
+
+use clap::ValueEnum;
+use lalrpop_util::{lalrpop_mod, ParseError};
+
+pub use ast::{BinaryOp, Expr, Filter, Import, Key, Pattern, Query, Template};
+pub use func::{Arity, FuncError, FunctionRegistry};
+pub use module::{LoadError, ModuleLoader};
+pub use query::{handle_query, handle_query_in, handle_query_with, Env, QueryError};
+pub use scheme::ast::{KeywordStyle, ReprOptions, Value};
+
+lalrpop_mod!(#[allow(clippy::empty_line_after_outer_attr)] pub lsq, "/src/lsq.rs");
+lalrpop_mod!(#[allow(clippy::empty_line_after_outer_attr)] pub scheme_parser, "/src/scheme_parser.rs");
+
+pub type QueryParseError = ParseError<usize, token::Token, lexer::LexicalError>;
+pub type SchemeParseError = ParseError<usize, scheme::token::Token, scheme::lexer::LexicalError>;
+
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FormatOptions {
+    Data,
+    Code,
+    Compact,
+}
+
+/// Compiles a lsq query
+pub fn compile(query: &str) -> Result<Query, QueryParseError> {
+    lsq::QueryParser::new().parse(lexer::Lexer::new(query))
+}
+
+/// Parses Scheme source into its top level values
+pub fn parse_scheme(source: &str) -> Result<Vec<Box<Value>>, SchemeParseError> {
+    scheme_parser::SchemeParser::new().parse(scheme::lexer::Lexer::new(source))
+}
+
+/// Formats the results of a query, one per line
+pub fn format_values(
+    values: &[Box<Value>],
+    format: FormatOptions,
+    options: &ReprOptions,
+) -> String {
+    values
+        .iter()
+        .map(|value| match format {
+            FormatOptions::Compact => value.compact_repr(options),
+            FormatOptions::Data => value.data_repr(options),
+            FormatOptions::Code => value.code_repr(options),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -1,27 +1,22 @@
-#![allow(unused)]
+#![allow(clippy::vec_box)]
 
-mod ast;
-mod func;
-mod lexer;
-mod query;
-mod scheme;
-mod token;
-
-use std::io::Read;
+use std::{
+    io::{IsTerminal, Read},
+    path::{Path, PathBuf},
+};
 
 use anyhow::Result as AnyResult;
-use clap::{Parser, ValueEnum};
-use lalrpop_util::lalrpop_mod;
+use clap::Parser;
+use lsq::{
+    diagnostic, Env, FormatOptions, FunctionRegistry, LoadError, ModuleLoader, ReprOptions, Value,
+};
 use syntect::{
     easy::HighlightLines,
     highlighting::{Style, ThemeSet},
-    parsing::{SyntaxReference, SyntaxSet},
+    parsing::SyntaxSet,
     util::LinesWithEndings,
 };
 
-lalrpop_mod!(pub lsq, "/src/lsq.rs");
-lalrpop_mod!(pub scheme_parser, "/src/scheme_parser.rs");
-
 #[derive(Debug, Parser)]
 #[clap(
     name = "lsq",
@@ -33,28 +28,76 @@ struct Sq {
     /// The query to execute
     query: String,
 
-    /// The file to read from (if not provided, will read from stdin)
+    /// The file to read from (if not provided, will read from stdin). With
+    /// `--args`, it's the first of the strings in `$ARGS` instead
     file: Option<String>,
 
     /// Format the output
     #[clap(short, long, default_value = "data")]
     format: FormatOptions,
 
+    /// The maximum line width of the output
+    #[clap(short, long, default_value = "80")]
+    width: usize,
+
+    /// Print booleans as `#true` and `#false` instead of `#t` and `#f`
+    #[clap(long, default_value = "false")]
+    long_bools: bool,
+
     #[clap(short = 'M', long, default_value = "false")]
     monochrome: bool,
 
+    /// Print strings without quotes and escapes, and chars without `#\`
     #[clap(short, long, default_value = "false")]
     raw: bool,
 
     #[clap(long = "ast", default_value = "false")]
     show_query: bool,
+
+    /// A directory to search the imported modules in, before `~/.lsq`
+    #[clap(short = 'L', value_name = "DIR")]
+    library_paths: Vec<PathBuf>,
+
+    /// Binds `$NAME` to the string VALUE
+    #[clap(long = "arg", num_args = 2, value_names = ["NAME", "VALUE"])]
+    string_args: Vec<String>,
+
+    /// Binds `$NAME` to the s-expression SEXP
+    #[clap(long = "argsexp", num_args = 2, value_names = ["NAME", "SEXP"])]
+    sexp_args: Vec<String>,
+
+    /// Binds `$NAME` to the list of the values in FILE
+    #[clap(long = "slurpfile", num_args = 2, value_names = ["NAME", "FILE"])]
+    slurp_files: Vec<String>,
+
+    /// The arguments after QUERY are strings, available in `$ARGS`, and the
+    /// input is read from stdin
+    #[clap(long = "args", default_value = "false")]
+    args: bool,
+
+    /// The strings given with `--args`
+    #[clap(value_name = "ARGS", requires = "args")]
+    positional_args: Vec<String>,
 }
 
-#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Eq, PartialOrd, Ord)]
-enum FormatOptions {
-    Data,
-    Code,
-    Compact,
+impl Sq {
+    /// Colors would end up in the file stderr is redirected to
+    fn color_diagnostics(&self) -> bool {
+        !self.monochrome && std::io::stderr().is_terminal()
+    }
+
+    /// Like jq, every argument after the query is a string under `--args`, so
+    /// FILE is only a file without it
+    fn input_file(&self) -> Option<&str> {
+        self.file.as_deref().filter(|_| !self.args)
+    }
+
+    fn positional_args(&self) -> impl Iterator<Item = &String> {
+        self.file
+            .iter()
+            .filter(|_| self.args)
+            .chain(&self.positional_args)
+    }
 }
 
 fn main() -> AnyResult<()> {
@@ -63,16 +106,60 @@ fn main() -> AnyResult<()> {
 
     let args = Sq::parse();
 
-    let lexer = lexer::Lexer::new(&args.query);
-    let result_query = lsq::QueryParser::new().parse(lexer);
+    let query = match lsq::compile(&args.query) {
+        Ok(query) => query,
+        Err(err) => {
+            let report = diagnostic::parse_error_report(
+                "<query>",
+                &args.query,
+                &err,
+                args.color_diagnostics(),
+            );
+            eprint!("{}", report);
+            std::process::exit(3);
+        }
+    };
+
+    let loader = match std::env::var_os("HOME") {
+        Some(home) => ModuleLoader::with_home(args.library_paths.clone(), Path::new(&home)),
+        None => ModuleLoader::new(args.library_paths.clone()),
+    };
+    let query = match loader.load(query) {
+        Ok(query) => query,
+        Err(LoadError::Parse {
+            path,
+            source,
+            error,
+        }) => {
+            let source_name = path.display().to_string();
+            let report = diagnostic::parse_error_report(
+                &source_name,
+                &source,
+                &error,
+                args.color_diagnostics(),
+            );
+            eprint!("{}", report);
+            std::process::exit(3);
+        }
+        Err(err) => {
+            eprintln!("lsq: error: {}", err);
+            std::process::exit(3);
+        }
+    };
 
     if args.show_query {
-        println!("{:#?}", result_query?.filters());
+        println!("{:#?}", query);
         return Ok(());
     }
 
-    let content = match args.file {
-        Some(ref file) => std::fs::read_to_string(file)
+    let env = variables(&args)?
+        .into_iter()
+        .fold(Env::new(&FunctionRegistry::with_builtins()), |env, (name, value)| {
+            env.with_variable(name, value)
+        });
+
+    let content = match args.input_file() {
+        Some(file) => std::fs::read_to_string(file)
             .map_err(|err| anyhow::anyhow!("Could not read file {}: {}", file, err))?,
         None => {
             let mut buffer = String::new();
@@ -81,34 +168,22 @@ fn main() -> AnyResult<()> {
         }
     };
 
-    let query = result_query?;
-
-    let lexer = scheme::lexer::Lexer::new(&content);
-    let branches = scheme_parser::SchemeParser::new().parse(lexer)?;
-
-    let theme_set = syntect::highlighting::ThemeSet::load_defaults();
-
-    let branches = query::handle_query(query, branches);
-
-    let branch_str = match args.format {
-        FormatOptions::Compact => branches
-            .into_iter()
-            .map(|b| b.compact_repr(args.raw))
-            .collect::<Vec<_>>()
-            .join("\n"),
-        FormatOptions::Data => branches
-            .into_iter()
-            .map(|b| b.data_repr(args.raw))
-            .collect::<Vec<_>>()
-            .join("\n"),
-        FormatOptions::Code => branches
-            .into_iter()
-            .map(|b| b.code_repr(args.raw))
-            .collect::<Vec<_>>()
-            .join("\n"),
+    let source_name = args.input_file().unwrap_or("<stdin>");
+    let branches = parse_scheme_or_exit(source_name, &content, args.color_diagnostics());
+
+    let branches = match lsq::handle_query_in(query, branches, &env) {
+        Ok(branches) => branches,
+        Err(err) => {
+            eprintln!("lsq: error: {}", err);
+            std::process::exit(5);
+        }
     };
 
-    if args.monochrome {
+    let options = ReprOptions::new(args.raw, args.width, args.long_bools);
+    let branch_str = lsq::format_values(&branches, args.format, &options);
+
+    // Colors would end up in the input of whatever lsq is piped into
+    if args.monochrome || !std::io::stdout().is_terminal() {
         println!("{}", branch_str);
     } else {
         let syntax = ps.find_syntax_by_extension("scm").unwrap();
@@ -123,3 +198,63 @@ fn main() -> AnyResult<()> {
 
     Ok(())
 }
+
+/// The variables given on the command line, and `$ARGS` with all of them:
+/// `(positional (args ...) named (name value ...))`
+fn variables(args: &Sq) -> AnyResult<Vec<(String, Value)>> {
+    let mut named = vec![];
+    for pair in args.string_args.chunks(2) {
+        named.push((pair[0].clone(), Value::String(pair[1].clone())));
+    }
+    for pair in args.sexp_args.chunks(2) {
+        let source_name = format!("--argsexp {}", pair[0]);
+        let mut values = parse_scheme_or_exit(&source_name, &pair[1], args.color_diagnostics());
+        if values.len() != 1 {
+            eprintln!(
+                "lsq: error: {}: expected a single value, got {}",
+                source_name,
+                values.len()
+            );
+            std::process::exit(2);
+        }
+        named.push((pair[0].clone(), *values.remove(0)));
+    }
+    for pair in args.slurp_files.chunks(2) {
+        let content = std::fs::read_to_string(&pair[1])
+            .map_err(|err| anyhow::anyhow!("Could not read file {}: {}", pair[1], err))?;
+        let values = parse_scheme_or_exit(&pair[1], &content, args.color_diagnostics());
+        named.push((pair[0].clone(), Value::List(values)));
+    }
+
+    let positional = args
+        .positional_args()
+        .map(|arg| Box::new(Value::String(arg.clone())))
+        .collect();
+    let named_plist = named
+        .iter()
+        .flat_map(|(name, value)| [Value::Symbol(name.clone()), value.clone()])
+        .map(Box::new)
+        .collect();
+    let all_args = Value::List(vec![
+        Box::new(Value::Symbol("positional".to_owned())),
+        Box::new(Value::List(positional)),
+        Box::new(Value::Symbol("named".to_owned())),
+        Box::new(Value::List(named_plist)),
+    ]);
+    named.push(("ARGS".to_owned(), all_args));
+
+    Ok(named)
+}
+
+/// Parses Scheme source, exiting with code 2 after reporting the error if it
+/// isn't valid
+fn parse_scheme_or_exit(source_name: &str, source: &str, color: bool) -> Vec<Box<Value>> {
+    match lsq::parse_scheme(source) {
+        Ok(values) => values,
+        Err(err) => {
+            let report = diagnostic::parse_error_report(source_name, source, &err, color);
+            eprint!("{}", report);
+            std::process::exit(2);
+        }
+    }
+}
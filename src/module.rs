@@ -0,0 +1,279 @@
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    ast::{Import, Query},
+    QueryParseError,
+};
+
+/// The extension added to module paths that don't have one
+const MODULE_EXTENSION: &str = "lsq";
+
+/// An error raised while loading the modules of a query
+#[derive(Debug)]
+pub enum LoadError {
+    /// The module isn't in the directory of the module importing it nor in
+    /// the search path
+    NotFound { path: String },
+    Io { path: PathBuf, error: io::Error },
+    Parse {
+        path: PathBuf,
+        source: String,
+        error: QueryParseError,
+    },
+    /// A module can only contain imports and definitions
+    NotAModule { path: PathBuf },
+    /// The module imports itself, directly or not
+    Cycle { path: PathBuf },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::NotFound { path } => {
+                write!(f, "module `{}` not found in the search path", path)
+            }
+            LoadError::Io { path, error } => {
+                write!(f, "could not read {}: {}", path.display(), error)
+            }
+            LoadError::Parse { path, error, .. } => write!(f, "{}: {}", path.display(), error),
+            LoadError::NotAModule { path } => write!(
+                f,
+                "{} is not a module, it must only contain imports and definitions",
+                path.display()
+            ),
+            LoadError::Cycle { path } => write!(f, "{} imports itself", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// Finds and parses the modules imported by a query
+#[derive(Debug, Clone, Default)]
+pub struct ModuleLoader {
+    search_paths: Vec<PathBuf>,
+    /// A module included in every query
+    prelude: Option<PathBuf>,
+}
+
+impl ModuleLoader {
+    pub fn new(search_paths: Vec<PathBuf>) -> Self {
+        ModuleLoader {
+            search_paths,
+            prelude: None,
+        }
+    }
+
+    /// Like `new`, with `.lsq` in the home directory as the prelude if it's a
+    /// file, or at the end of the search path if it's a directory
+    pub fn with_home(search_paths: Vec<PathBuf>, home: &Path) -> Self {
+        let mut loader = Self::new(search_paths);
+        let lsq = home.join(".lsq");
+        if lsq.is_file() {
+            loader.prelude = Some(lsq);
+        } else if lsq.is_dir() {
+            loader.search_paths.push(lsq);
+        }
+        loader
+    }
+
+    /// Loads the modules imported by the query, the definitions of the
+    /// included modules are added before the definitions of the query
+    pub fn load(&self, query: Query) -> Result<Query, LoadError> {
+        let prelude = self
+            .prelude
+            .iter()
+            .map(|path| Import::new(path.to_string_lossy().into_owned(), None));
+        let imports = prelude.chain(query.imports().iter().cloned()).collect();
+        let query = Query::new(imports, query.defs().clone(), query.filters().clone());
+        self.load_imports(query, None, &mut vec![])
+    }
+
+    /// `dir` is the directory of the module being loaded and `loading` the
+    /// modules being loaded, to detect cycles
+    fn load_imports(
+        &self,
+        query: Query,
+        dir: Option<&Path>,
+        loading: &mut Vec<PathBuf>,
+    ) -> Result<Query, LoadError> {
+        let mut imports = vec![];
+        let mut defs = vec![];
+
+        for import in query.imports() {
+            let module = self.load_module(import.path(), dir, loading)?;
+            match import.alias() {
+                Some(_) => imports.push(import.clone().with_module(module)),
+                None => {
+                    imports.extend(module.imports().iter().cloned());
+                    defs.extend(module.defs().iter().cloned());
+                }
+            }
+        }
+        defs.extend(query.defs().iter().cloned());
+
+        Ok(Query::new(imports, defs, query.filters().clone()))
+    }
+
+    fn load_module(
+        &self,
+        path: &str,
+        dir: Option<&Path>,
+        loading: &mut Vec<PathBuf>,
+    ) -> Result<Query, LoadError> {
+        let path = self.resolve(path, dir).ok_or_else(|| LoadError::NotFound {
+            path: path.to_owned(),
+        })?;
+        let path = path.canonicalize().unwrap_or(path);
+        if loading.contains(&path) {
+            return Err(LoadError::Cycle { path });
+        }
+
+        let source = std::fs::read_to_string(&path).map_err(|error| LoadError::Io {
+            path: path.clone(),
+            error,
+        })?;
+        let module = match crate::compile(&source) {
+            Ok(module) => module,
+            Err(error) => {
+                return Err(LoadError::Parse {
+                    path,
+                    source,
+                    error,
+                })
+            }
+        };
+        if !module.filters().is_empty() {
+            return Err(LoadError::NotAModule { path });
+        }
+
+        loading.push(path.clone());
+        let module = self.load_imports(module, path.parent(), loading);
+        loading.pop();
+        module
+    }
+
+    /// Looks for the module in the directory of the module importing it, then
+    /// in the search path. `.lsq` is tried after paths without an extension.
+    fn resolve(&self, path: &str, dir: Option<&Path>) -> Option<PathBuf> {
+        let path = Path::new(path);
+        let candidates: Vec<PathBuf> = if path.is_absolute() {
+            vec![path.to_owned()]
+        } else {
+            dir.into_iter()
+                .chain(self.search_paths.iter().map(PathBuf::as_path))
+                .map(|dir| dir.join(path))
+                .collect()
+        };
+
+        candidates
+            .into_iter()
+            .flat_map(|candidate| {
+                let with_extension = candidate
+                    .extension()
+                    .is_none()
+                    .then(|| candidate.with_extension(MODULE_EXTENSION));
+                std::iter::once(candidate).chain(with_extension)
+            })
+            .find(|candidate| candidate.is_file())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, name: &str, source: &str) {
+        std::fs::write(dir.join(name), source).unwrap();
+    }
+
+    fn load(loader: &ModuleLoader, query: &str) -> Result<Query, LoadError> {
+        loader.load(crate::compile(query).unwrap())
+    }
+
+    fn def_names(query: &Query) -> Vec<String> {
+        query.defs().iter().map(|def| def.name().clone()).collect()
+    }
+
+    #[test]
+    fn cycles_are_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "a.lsq", "include \"b\";");
+        write(dir.path(), "b.lsq", "include \"a\";");
+
+        let loader = ModuleLoader::new(vec![dir.path().to_owned()]);
+        assert!(matches!(
+            load(&loader, "include \"a\"; ;"),
+            Err(LoadError::Cycle { path }) if path.ends_with("a.lsq")
+        ));
+    }
+
+    #[test]
+    fn modules_only_contain_definitions() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "query.lsq", "def f: ;; ;name");
+
+        let loader = ModuleLoader::new(vec![dir.path().to_owned()]);
+        assert!(matches!(
+            load(&loader, "include \"query\"; ;"),
+            Err(LoadError::NotAModule { .. })
+        ));
+    }
+
+    #[test]
+    fn modules_are_resolved_from_the_importing_module_first() {
+        let root = tempfile::tempdir().unwrap();
+        let lib = tempfile::tempdir().unwrap();
+        write(root.path(), "main.lsq", "include \"util\";");
+        write(root.path(), "util.lsq", "def from_root: ;;");
+        write(lib.path(), "util.lsq", "def from_lib: ;;");
+
+        let loader = ModuleLoader::new(vec![lib.path().to_owned(), root.path().to_owned()]);
+        let query = load(&loader, "include \"main\"; ;").unwrap();
+        assert_eq!(def_names(&query), vec!["from_root"]);
+        let query = load(&loader, "include \"util\"; ;").unwrap();
+        assert_eq!(def_names(&query), vec!["from_lib"]);
+    }
+
+    #[test]
+    fn search_path_comes_before_home() {
+        let home = tempfile::tempdir().unwrap();
+        let lib = tempfile::tempdir().unwrap();
+        std::fs::create_dir(home.path().join(".lsq")).unwrap();
+        write(&home.path().join(".lsq"), "util.lsq", "def from_home: ;;");
+        write(&home.path().join(".lsq"), "home.lsq", "def only_home: ;;");
+        write(lib.path(), "util.lsq", "def from_lib: ;;");
+
+        let loader = ModuleLoader::with_home(vec![lib.path().to_owned()], home.path());
+        let query = load(&loader, "include \"util\"; include \"home\"; ;").unwrap();
+        assert_eq!(def_names(&query), vec!["from_lib", "only_home"]);
+    }
+
+    #[test]
+    fn a_home_file_is_the_prelude() {
+        let home = tempfile::tempdir().unwrap();
+        write(home.path(), ".lsq", "def from_prelude: ;;");
+
+        let loader = ModuleLoader::with_home(vec![], home.path());
+        let query = load(&loader, "def own: ;; ;").unwrap();
+        assert_eq!(def_names(&query), vec!["from_prelude", "own"]);
+    }
+
+    #[test]
+    fn the_extension_is_optional() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "util.lsq", "def with_extension: ;;");
+        write(dir.path(), "other", "def without_extension: ;;");
+        write(dir.path(), "other.lsq", "def with_extension: ;;");
+
+        let loader = ModuleLoader::new(vec![dir.path().to_owned()]);
+        let query = load(&loader, "include \"util\"; include \"other\"; ;").unwrap();
+        assert_eq!(
+            def_names(&query),
+            vec!["with_extension", "without_extension"]
+        );
+    }
+}
@@ -1,14 +1,14 @@
-use std::{str::FromStr, usize};
+use std::{borrow::Cow, fmt, rc::Rc, str::FromStr};
 
 use derive_getters::Getters;
 use derive_new::new;
 use once_cell::sync::Lazy;
 
 use crate::{
-    ast::{Expr, Filter, Query},
-    func::BUILTIN_FUNCS,
+    ast::{BinaryOp, Expr, Filter, FuncDef, Key, Pattern, Query, Template},
+    func::{Arity, FuncError, FunctionRegistry},
     lsq,
-    scheme::ast::Value as SchemeValue,
+    scheme::{ast::Value as SchemeValue, lisp, pattern::match_pattern},
 };
 
 #[derive(Debug, Clone, new, Getters)]
@@ -17,134 +17,524 @@ struct QueryState {
     branches: Vec<Box<SchemeValue>>,
 }
 
-pub fn handle_query(query: Query, content: Vec<Box<SchemeValue>>) -> Vec<Box<SchemeValue>> {
-    let mut branches = content;
+/// An error raised while running a query against some input
+#[derive(Debug, Clone)]
+pub enum QueryError {
+    /// The filter can't be applied to a value of this type
+    WrongType {
+        filter: Filter,
+        value: Box<SchemeValue>,
+        expected: &'static str,
+    },
+    /// `;key` didn't find `key`, or `key` was the last element of the list
+    KeyNotFound {
+        filter: Filter,
+        value: Box<SchemeValue>,
+        key: Key,
+    },
+    IndexOutOfBounds {
+        filter: Filter,
+        value: Box<SchemeValue>,
+        index: i64,
+    },
+    UnknownFunction {
+        filter: Filter,
+        name: String,
+    },
+    /// A function defined with `def` was called with the wrong number of
+    /// arguments
+    WrongArity {
+        filter: Filter,
+        expected: Arity,
+        got: usize,
+    },
+    UnknownVariable {
+        filter: Filter,
+        name: String,
+    },
+    /// The value bound with `as` doesn't have the shape of the pattern
+    PatternMismatch {
+        filter: Filter,
+        value: Box<SchemeValue>,
+        pattern: Pattern,
+    },
+    /// An integer operation overflowed on this input
+    Overflow {
+        filter: Filter,
+        value: Box<SchemeValue>,
+    },
+    DivisionByZero {
+        filter: Filter,
+        value: Box<SchemeValue>,
+    },
+    /// A builtin function failed on this value
+    FuncError {
+        filter: Filter,
+        value: Box<SchemeValue>,
+        error: FuncError,
+    },
+}
 
-    for filter in query.filters() {
-        branches = handle_filter(filter.as_ref(), &branches);
+impl QueryError {
+    pub fn filter(&self) -> &Filter {
+        match self {
+            QueryError::WrongType { filter, .. }
+            | QueryError::KeyNotFound { filter, .. }
+            | QueryError::IndexOutOfBounds { filter, .. }
+            | QueryError::UnknownFunction { filter, .. }
+            | QueryError::WrongArity { filter, .. }
+            | QueryError::UnknownVariable { filter, .. }
+            | QueryError::PatternMismatch { filter, .. }
+            | QueryError::Overflow { filter, .. }
+            | QueryError::DivisionByZero { filter, .. }
+            | QueryError::FuncError { filter, .. } => filter,
+        }
+    }
+
+    pub fn value(&self) -> Option<&SchemeValue> {
+        match self {
+            QueryError::WrongType { value, .. }
+            | QueryError::KeyNotFound { value, .. }
+            | QueryError::IndexOutOfBounds { value, .. }
+            | QueryError::PatternMismatch { value, .. }
+            | QueryError::Overflow { value, .. }
+            | QueryError::DivisionByZero { value, .. }
+            | QueryError::FuncError { value, .. } => Some(value),
+            QueryError::UnknownFunction { .. }
+            | QueryError::WrongArity { .. }
+            | QueryError::UnknownVariable { .. } => None,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            QueryError::WrongType { expected, .. } => format!("expected {}", expected),
+            QueryError::KeyNotFound { key, .. } => format!("no value found for key `{}`", key),
+            QueryError::IndexOutOfBounds { value, index, .. } => {
+                let len = value.iter_values().map_or(0, |l| l.count());
+                format!("index {} is out of bounds for length {}", index, len)
+            }
+            QueryError::UnknownFunction { name, .. } => format!("unknown function `{}`", name),
+            QueryError::WrongArity { expected, got, .. } => {
+                format!("expected {}, got {}", expected, got)
+            }
+            QueryError::UnknownVariable { name, .. } => format!("unknown variable `${}`", name),
+            QueryError::PatternMismatch { pattern, .. } => {
+                format!("expected a value matching {}", pattern)
+            }
+            QueryError::Overflow { .. } => "integer overflow".to_owned(),
+            QueryError::DivisionByZero { .. } => "division by zero".to_owned(),
+            QueryError::FuncError { error, .. } => error.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\n  in filter: {}", self.message(), self.filter())?;
+        if let Some(value) = self.value() {
+            write!(f, "\n  on value: {}", value)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+type QueryResult = Result<Vec<Box<SchemeValue>>, QueryError>;
+
+/// What a filter can call: the registry, and the functions, parameters and
+/// variables in lexical scope
+#[derive(Clone)]
+pub struct Env {
+    funcs: Rc<FunctionRegistry>,
+    scope: Option<Rc<Scope>>,
+}
+
+/// A name bound in a linked list of scopes
+struct Scope {
+    name: String,
+    binding: Binding,
+    parent: Option<Rc<Scope>>,
+}
+
+enum Binding {
+    /// A function defined with `def`. Its body sees the scope it's bound in,
+    /// so it can call itself.
+    Def(Rc<FuncDef>),
+    /// A parameter, evaluated in the environment of the caller
+    Param(Box<Expr>, Env),
+    /// A module imported with `import "path" as name;`, its functions are
+    /// called as `name::func`
+    Module(Env),
+    /// A variable bound with `as`
+    Var(Box<SchemeValue>),
+}
+
+impl Env {
+    pub fn new(funcs: &FunctionRegistry) -> Self {
+        Env {
+            funcs: Rc::new(funcs.clone()),
+            scope: None,
+        }
+    }
+
+    /// Binds `$name` to the value in the queries run in this environment
+    pub fn with_variable(&self, name: impl Into<String>, value: SchemeValue) -> Self {
+        self.bind(name.into(), Binding::Var(Box::new(value)))
+    }
+
+    fn bind(&self, name: String, binding: Binding) -> Self {
+        Env {
+            funcs: self.funcs.clone(),
+            scope: Some(Rc::new(Scope {
+                name,
+                binding,
+                parent: self.scope.clone(),
+            })),
+        }
+    }
+
+    /// The innermost scope binding `name`, or `func` in the module `module`
+    /// for `module::func`
+    fn lookup(&self, name: &str) -> Option<&Rc<Scope>> {
+        let (module, name) = match name.split_once("::") {
+            Some((module, name)) => (Some(module), name),
+            None => (None, name),
+        };
+
+        let mut scope = self.scope.as_ref();
+        while let Some(current) = scope {
+            match (&current.binding, module) {
+                (Binding::Module(env), Some(module)) if current.name == module => {
+                    return env.lookup(name);
+                }
+                (Binding::Def(_) | Binding::Param(..), None) if current.name == name => {
+                    return Some(current);
+                }
+                _ => {}
+            }
+            scope = current.parent.as_ref();
+        }
+        None
+    }
+
+    fn variable(&self, name: &str) -> Option<&SchemeValue> {
+        let mut scope = self.scope.as_ref();
+        while let Some(current) = scope {
+            match &current.binding {
+                Binding::Var(value) if current.name == name => return Some(value),
+                _ => scope = current.parent.as_ref(),
+            }
+        }
+        None
+    }
+
+    /// Binds the variables of the pattern, `None` if the value doesn't match
+    /// it
+    fn bind_pattern(&self, pattern: &Pattern, value: &SchemeValue) -> Option<Self> {
+        match pattern {
+            Pattern::Variable(name) => {
+                Some(self.bind(name.clone(), Binding::Var(Box::new(value.clone()))))
+            }
+            Pattern::List { elements, rest } => {
+                let values = match value {
+                    SchemeValue::List(l) | SchemeValue::Vector(l) => l.as_slice(),
+                    SchemeValue::Nil => &[],
+                    _ => return None,
+                };
+                let len_matches = match rest {
+                    Some(_) => values.len() >= elements.len(),
+                    None => values.len() == elements.len(),
+                };
+                if !len_matches {
+                    return None;
+                }
+
+                let env = elements
+                    .iter()
+                    .zip(values)
+                    .try_fold(self.clone(), |env, (pattern, value)| {
+                        env.bind_pattern(pattern, value)
+                    })?;
+                Some(match rest {
+                    Some(name) => {
+                        let rest = values[elements.len()..].to_vec();
+                        let rest = match value {
+                            SchemeValue::Vector(_) => SchemeValue::Vector(rest),
+                            _ => SchemeValue::List(rest),
+                        };
+                        env.bind(name.clone(), Binding::Var(Box::new(rest)))
+                    }
+                    None => env,
+                })
+            }
+        }
+    }
+
+    /// Binds the modules imported by the query and the functions it defines
+    fn with_query(&self, query: &Query) -> Self {
+        let env = query.imports().iter().fold(self.clone(), |env, import| {
+            match (import.alias(), import.module()) {
+                (Some(alias), Some(module)) => {
+                    let module_env = Env {
+                        funcs: self.funcs.clone(),
+                        scope: None,
+                    };
+                    env.bind(
+                        alias.clone(),
+                        Binding::Module(module_env.with_query(module)),
+                    )
+                }
+                // Included modules are merged into the query when loaded, and
+                // the modules that weren't loaded have nothing to bind
+                _ => env,
+            }
+        });
+
+        query.defs().iter().fold(env, |env, def| {
+            env.bind(def.name().clone(), Binding::Def(Rc::new(def.clone())))
+        })
+    }
+
+    fn is_param(&self, name: &str) -> bool {
+        self.lookup(name)
+            .is_some_and(|scope| matches!(scope.binding, Binding::Param(..)))
+    }
+
+    /// The function `name` refers to and the environment it's defined in,
+    /// following the parameters bound to a function. `None` if `name` isn't
+    /// a function, or is a parameter bound to some other filter.
+    fn resolve_function(&self, name: &str) -> Option<(String, Env)> {
+        let Some(scope) = self.lookup(name) else {
+            return self
+                .funcs
+                .contains(name)
+                .then(|| (name.to_owned(), self.clone()));
+        };
+        match &scope.binding {
+            Binding::Param(expr, caller) => match expr.as_ref() {
+                Expr::Filter(filter) => match filter.as_ref() {
+                    Filter::FuncCall { func, args } if args.is_empty() => {
+                        caller.resolve_function(func)
+                    }
+                    _ => None,
+                },
+                Expr::Value(_) | Expr::Variable(_) => None,
+            },
+            _ => Some((name.to_owned(), self.clone())),
+        }
     }
 
-    branches
+    /// The registry a builtin is called with. The symbol arguments naming a
+    /// function defined in the query, or a parameter bound to a function, are
+    /// registered in this copy of the registry only, so the builtins taking a
+    /// function name can call them.
+    fn registry_for(&self, args: &[Box<Expr>]) -> Cow<'_, FunctionRegistry> {
+        let mut funcs = Cow::Borrowed(self.funcs.as_ref());
+        for arg in args {
+            let Expr::Value(value) = arg.as_ref() else {
+                continue;
+            };
+            let SchemeValue::Symbol(name) = value.as_ref() else {
+                continue;
+            };
+            let Some((target, env)) = self.resolve_function(name) else {
+                continue;
+            };
+            let Some(scope) = env.lookup(&target).cloned() else {
+                // A parameter bound to a builtin is called by the builtin's
+                // name
+                if target != *name {
+                    funcs.to_mut().register(
+                        name.clone(),
+                        self.funcs.get(&target).unwrap().arity(),
+                        move |funcs, value, args| funcs.call(&target, value, args),
+                    );
+                }
+                continue;
+            };
+
+            let name = name.clone();
+            funcs.to_mut().register(
+                name.clone(),
+                Arity::Exactly(scope.arity()),
+                move |_, value, args| {
+                    let args: Vec<_> = args.into_iter().map(|v| Box::new(Expr::Value(v))).collect();
+                    let filter = Filter::FuncCall {
+                        func: name.clone(),
+                        args: args.clone(),
+                    };
+                    let mut results = call_user(&filter, &scope, &args, &[value], &env)
+                        .map_err(|error| FuncError::QueryError(Box::new(error)))?;
+                    Ok(match results.len() {
+                        0 => None,
+                        1 => results.pop(),
+                        _ => Some(Box::new(SchemeValue::List(results))),
+                    })
+                },
+            );
+        }
+        funcs
+    }
 }
 
-pub fn handle_query_scm(query: Query, content: SchemeValue) -> Vec<Box<SchemeValue>> {
-    let mut branches = vec![Box::new(content)];
+impl Scope {
+    fn arity(&self) -> usize {
+        match &self.binding {
+            Binding::Def(def) => def.params().len(),
+            Binding::Param(..) | Binding::Module(_) | Binding::Var(_) => 0,
+        }
+    }
+}
+
+/// Runs the query with the builtin functions
+pub fn handle_query(query: Query, content: Vec<Box<SchemeValue>>) -> QueryResult {
+    handle_query_with(query, content, &FunctionRegistry::with_builtins())
+}
+
+/// Runs the query with the functions of `funcs`
+pub fn handle_query_with(
+    query: Query,
+    content: Vec<Box<SchemeValue>>,
+    funcs: &FunctionRegistry,
+) -> QueryResult {
+    handle_query_in(query, content, &Env::new(funcs))
+}
+
+/// Runs the query with the functions and the variables of `env`
+pub fn handle_query_in(query: Query, content: Vec<Box<SchemeValue>>, env: &Env) -> QueryResult {
+    lisp::clear_cache();
+    run_query(&query, content, env)
+}
+
+pub fn handle_query_scm(
+    query: Query,
+    content: SchemeValue,
+    funcs: &FunctionRegistry,
+) -> QueryResult {
+    handle_query_in(query, vec![Box::new(content)], &Env::new(funcs))
+}
+
+/// Runs the query in `env` extended with the modules it imports and the
+/// functions it defines
+fn run_query(query: &Query, mut branches: Vec<Box<SchemeValue>>, env: &Env) -> QueryResult {
+    let env = env.with_query(query);
 
     for filter in query.filters() {
-        branches = handle_filter(filter.as_ref(), &branches);
+        branches = handle_filter(filter.as_ref(), &branches, &env)?;
     }
 
-    branches
+    Ok(branches)
 }
 
-pub fn handle_filter(filter: &Filter, branches: &[Box<SchemeValue>]) -> Vec<Box<SchemeValue>> {
+pub fn handle_filter(filter: &Filter, branches: &[Box<SchemeValue>], env: &Env) -> QueryResult {
+    let wrong_type = |value: Box<SchemeValue>| QueryError::WrongType {
+        filter: filter.clone(),
+        value,
+        expected: "a list or a vector",
+    };
+
     match filter {
-        Filter::Identity => branches.to_vec(),
+        Filter::Identity => Ok(branches.to_vec()),
         Filter::Tail(key) => branches
-            .into_iter()
+            .iter()
             .cloned()
             .map(|branch| match *branch {
-                SchemeValue::List(l) => Box::new(SchemeValue::List(
+                SchemeValue::List(l) => Ok(Box::new(SchemeValue::List(
                     l.into_iter()
                         .skip_while(|k| k.as_ref() != &SchemeValue::Symbol(key.clone()))
                         .skip(1)
                         .collect::<Vec<_>>(),
-                )),
-                SchemeValue::Vector(l) => Box::new(SchemeValue::Vector(
+                ))),
+                SchemeValue::Vector(l) => Ok(Box::new(SchemeValue::Vector(
                     l.into_iter()
                         .skip_while(|k| k.as_ref() != &SchemeValue::Symbol(key.clone()))
                         .skip(1)
                         .collect::<Vec<_>>(),
-                )),
-                _ => panic!("Expected list"),
+                ))),
+                _ => Err(wrong_type(branch)),
             })
-            .collect::<Vec<_>>(),
+            .collect(),
 
         Filter::Head(key) => branches
-            .into_iter()
+            .iter()
             .cloned()
             .map(|branch| match *branch {
-                SchemeValue::List(l) => Box::new(SchemeValue::List(
+                SchemeValue::List(l) => Ok(Box::new(SchemeValue::List(
                     l.into_iter()
                         .take_while(|k| k.as_ref() != &SchemeValue::Symbol(key.clone()))
                         .collect::<Vec<_>>(),
-                )),
-                SchemeValue::Vector(l) => Box::new(SchemeValue::Vector(
+                ))),
+                SchemeValue::Vector(l) => Ok(Box::new(SchemeValue::Vector(
                     l.into_iter()
                         .take_while(|k| k.as_ref() != &SchemeValue::Symbol(key.clone()))
                         .collect::<Vec<_>>(),
-                )),
-                _ => panic!("Expected list"),
+                ))),
+                _ => Err(wrong_type(branch)),
             })
-            .collect::<Vec<_>>(),
+            .collect(),
 
         Filter::Key(key) => branches
-            .into_iter()
-            .cloned()
-            .map(|branch| match *branch {
-                SchemeValue::List(l) | SchemeValue::Vector(l) => l
-                    .into_iter()
-                    .skip_while(|k| k.as_ref() != &SchemeValue::Symbol(key.clone()))
-                    .nth(1)
-                    .unwrap()
-                    .clone(),
-                _ => panic!("Expected list"),
+            .iter()
+            .map(|branch| match branch.as_ref() {
+                SchemeValue::List(l) | SchemeValue::Vector(l) | SchemeValue::Pair(l, _) => {
+                    lookup_key(l, key).ok_or_else(|| QueryError::KeyNotFound {
+                        filter: filter.clone(),
+                        value: branch.clone(),
+                        key: key.clone(),
+                    })
+                }
+                _ => Err(wrong_type(branch.clone())),
             })
-            .collect::<Vec<_>>(),
+            .collect(),
 
         Filter::Index(i) => branches
-            .into_iter()
-            .cloned()
-            .map(|branch| match *branch {
-                SchemeValue::List(l) | SchemeValue::Vector(l) => {
-                    let index = normalize_idx(*i, l.len());
-                    if index < l.len() {
-                        l[index].clone()
-                    } else {
-                        panic!("Index out of bounds")
-                    }
-                }
-                _ => panic!("Expected list"),
+            .iter()
+            .map(|branch| match branch.as_ref() {
+                SchemeValue::List(l) | SchemeValue::Vector(l) => normalize_idx(*i, l.len())
+                    .filter(|index| *index < l.len())
+                    .map(|index| l[index].clone())
+                    .ok_or_else(|| QueryError::IndexOutOfBounds {
+                        filter: filter.clone(),
+                        value: branch.clone(),
+                        index: *i,
+                    }),
+                _ => Err(wrong_type(branch.clone())),
             })
-            .collect::<Vec<_>>(),
+            .collect(),
 
         Filter::Slice(start, end) => branches
-            .into_iter()
+            .iter()
             .cloned()
             .map(|branch| match *branch {
                 SchemeValue::List(l) => {
-                    let start = start.map(|s| normalize_idx(s, l.len()));
-                    let end = end.map(|e| normalize_idx(e, l.len()));
-
-                    Box::new(match (start, end) {
-                        (None, None) => SchemeValue::List(l),
-                        (Some(start), None) => SchemeValue::List(l[start..].to_vec()),
-                        (None, Some(end)) => SchemeValue::List(l[..end].to_vec()),
-                        (Some(start), Some(end)) => SchemeValue::List(l[start..end].to_vec()),
-                    })
+                    let (start, end) = slice_bounds(*start, *end, l.len());
+                    Ok(Box::new(SchemeValue::List(l[start..end].to_vec())))
                 }
                 SchemeValue::Vector(l) => {
-                    let start = start.map(|s| normalize_idx(s, l.len()));
-                    let end = end.map(|e| normalize_idx(e, l.len()));
-
-                    Box::new(match (start, end) {
-                        (None, None) => SchemeValue::Vector(l),
-                        (Some(start), None) => SchemeValue::Vector(l[start..].to_vec()),
-                        (None, Some(end)) => SchemeValue::Vector(l[..end].to_vec()),
-                        (Some(start), Some(end)) => SchemeValue::Vector(l[start..end].to_vec()),
-                    })
+                    let (start, end) = slice_bounds(*start, *end, l.len());
+                    Ok(Box::new(SchemeValue::Vector(l[start..end].to_vec())))
                 }
-                _ => panic!("Expected list"),
+                _ => Err(wrong_type(branch)),
             })
-            .collect::<Vec<_>>(),
+            .collect(),
 
         Filter::Branch(filters) => {
+            // Like jq, every input produces the outputs of all the filters
+            // before moving on to the next input
             let mut final_branches = vec![];
-            for filter in filters {
-                final_branches.extend(handle_filter(filter.as_ref(), branches.as_ref()));
+            for branch in branches {
+                for filter in filters {
+                    final_branches.extend(handle_filter(
+                        filter.as_ref(),
+                        std::slice::from_ref(branch),
+                        env,
+                    )?);
+                }
             }
 
-            final_branches
+            Ok(final_branches)
         }
 
         Filter::ListIter => {
@@ -154,52 +544,669 @@ pub fn handle_filter(filter: &Filter, branches: &[Box<SchemeValue>]) -> Vec<Box<
                     SchemeValue::List(l) | SchemeValue::Vector(l) => {
                         final_branches.extend(l.clone())
                     }
-                    _ => panic!("Expected list"),
+                    SchemeValue::Pair(l, tail) => {
+                        final_branches.extend(l.clone());
+                        final_branches.push(tail.clone());
+                    }
+                    _ => return Err(wrong_type(branch.clone())),
                 }
             }
 
-            final_branches
+            Ok(final_branches)
         }
-        Filter::FuncCall { func, args } => {
-            let func = *BUILTIN_FUNCS.get(func.as_str()).unwrap();
+        Filter::Recurse => Ok(branches
+            .iter()
+            .flat_map(|branch| branch.recurse())
+            .map(|value| Box::new(value.clone()))
+            .collect()),
+        Filter::FuncCall { func: name, args } => {
+            // Functions defined in the query shadow the builtins
+            if let Some(scope) = env.lookup(name) {
+                return call_user(filter, scope, args, branches, env);
+            }
+            if !env.funcs.contains(name) {
+                return Err(QueryError::UnknownFunction {
+                    filter: filter.clone(),
+                    name: name.clone(),
+                });
+            }
+            let funcs = env.registry_for(args);
+            let function_arg = funcs.get(name).and_then(|function| function.function_arg());
             let mut final_branches = vec![];
 
             for branch in branches {
                 let args = args
-                    .into_iter()
-                    .map(|arg| match *arg.clone() {
-                        Expr::Filter(f) => {
-                            let result = handle_filter(&*f, vec![branch.clone()].as_ref());
-                            if result.len() == 1 {
-                                result[0].clone()
-                            } else {
-                                Box::new(SchemeValue::List(result))
+                    .iter()
+                    .enumerate()
+                    .map(|(i, arg)| {
+                        let result = match arg.as_ref() {
+                            Expr::Filter(f) => handle_filter(f, std::slice::from_ref(branch), env)?,
+                            // Parameters are filters, applied like the other
+                            // filter arguments, unless they are bound to a
+                            // function and the builtin takes a function name
+                            // there
+                            Expr::Value(v) => match v.as_ref() {
+                                SchemeValue::Symbol(param)
+                                    if env.is_param(param)
+                                        && (function_arg != Some(i)
+                                            || env.resolve_function(param).is_none()) =>
+                                {
+                                    let param = Filter::FuncCall {
+                                        func: param.clone(),
+                                        args: vec![],
+                                    };
+                                    handle_filter(&param, std::slice::from_ref(branch), env)?
+                                }
+                                _ => return Ok(v.clone()),
+                            },
+                            Expr::Variable(name) => {
+                                return Ok(Box::new(variable(name, env)?.clone()))
                             }
+                        };
+                        if result.len() == 1 {
+                            Ok(result[0].clone())
+                        } else {
+                            Ok(Box::new(SchemeValue::List(result)))
                         }
-                        Expr::Value(v) => v,
                     })
-                    .collect::<Vec<_>>();
-                let new_value = func(branch.clone(), args.clone()).unwrap();
+                    .collect::<Result<Vec<_>, _>>()?;
+                let new_value = funcs.call(name, branch.clone(), args).map_err(|error| {
+                    QueryError::FuncError {
+                        filter: filter.clone(),
+                        value: branch.clone(),
+                        error,
+                    }
+                })?;
                 if let Some(value) = new_value {
                     final_branches.push(value);
                 }
             }
 
-            final_branches
+            Ok(final_branches)
         }
         Filter::SubQuery(query) => {
             let mut final_branches = vec![];
             for branch in branches {
-                let new_branches = handle_query_scm(*query.clone(), *branch.clone());
-                final_branches.extend(new_branches);
+                final_branches.extend(run_query(query, vec![branch.clone()], env)?);
+            }
+
+            Ok(final_branches)
+        }
+        Filter::Expr(expr) => eval_expr(expr, branches, env),
+        Filter::Bind {
+            source,
+            pattern,
+            body,
+        } => {
+            // Like jq, the body gets the input of the binding
+            let mut final_branches = vec![];
+            for branch in branches {
+                for value in run_query(source, vec![branch.clone()], env)? {
+                    let env = env.bind_pattern(pattern, &value).ok_or_else(|| {
+                        QueryError::PatternMismatch {
+                            filter: filter.clone(),
+                            value,
+                            pattern: pattern.clone(),
+                        }
+                    })?;
+                    final_branches.extend(run_query(body, vec![branch.clone()], &env)?);
+                }
             }
 
-            final_branches
+            Ok(final_branches)
         }
-        Filter::Expr(_) => unimplemented!(),
+        Filter::If {
+            cond,
+            then,
+            otherwise,
+        } => {
+            // Like jq, each output of the condition produces the outputs of
+            // one of the branches, and a missing `else` keeps the input
+            let mut final_branches = vec![];
+            for branch in branches {
+                for test in run_query(cond, vec![branch.clone()], env)? {
+                    let input = vec![branch.clone()];
+                    match otherwise {
+                        _ if test.is_truthy() => {
+                            final_branches.extend(run_query(then, input, env)?)
+                        }
+                        Some(otherwise) => final_branches.extend(run_query(otherwise, input, env)?),
+                        None => final_branches.extend(input),
+                    }
+                }
+            }
+
+            Ok(final_branches)
+        }
+        Filter::Match { pattern, body } => {
+            let mut final_branches = vec![];
+            for branch in branches {
+                let Some(captures) = match_pattern(pattern, branch) else {
+                    continue;
+                };
+                let env = captures
+                    .into_iter()
+                    .fold(env.clone(), |env, (name, value)| {
+                        env.with_variable(name, value)
+                    });
+                final_branches.extend(run_query(body, vec![branch.clone()], &env)?);
+            }
+
+            Ok(final_branches)
+        }
+        Filter::Binary { op, lhs, rhs } => {
+            // Every output of the left side is combined with every output of
+            // the right side, unless `and` or `or` already know the result
+            let mut final_branches = vec![];
+            for branch in branches {
+                for left in run_query(lhs, vec![branch.clone()], env)? {
+                    let result = match op {
+                        BinaryOp::And if !left.is_truthy() => Some(false),
+                        BinaryOp::Or if left.is_truthy() => Some(true),
+                        _ => None,
+                    };
+                    if let Some(result) = result {
+                        final_branches.push(Box::new(SchemeValue::Bool(result)));
+                        continue;
+                    }
+                    for right in run_query(rhs, vec![branch.clone()], env)? {
+                        let value = binary_op(filter, *op, &left, &right, branch)?;
+                        final_branches.push(Box::new(value));
+                    }
+                }
+            }
+
+            Ok(final_branches)
+        }
+        Filter::Not(query) => Ok(branches
+            .iter()
+            .map(|branch| run_query(query, vec![branch.clone()], env))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .flatten()
+            .map(|value| Box::new(SchemeValue::Bool(!value.is_truthy())))
+            .collect()),
+        Filter::List(elements) => branches
+            .iter()
+            .map(|branch| {
+                Ok(Box::new(SchemeValue::List(construct(
+                    elements,
+                    std::slice::from_ref(branch),
+                    env,
+                )?)))
+            })
+            .collect(),
+        Filter::Vector(elements) => branches
+            .iter()
+            .map(|branch| {
+                Ok(Box::new(SchemeValue::Vector(construct(
+                    elements,
+                    std::slice::from_ref(branch),
+                    env,
+                )?)))
+            })
+            .collect(),
+        Filter::Quasiquote(template) => {
+            let mut final_branches = vec![];
+            for branch in branches {
+                final_branches.extend(instantiate(
+                    filter,
+                    template,
+                    std::slice::from_ref(branch),
+                    env,
+                )?);
+            }
+
+            Ok(final_branches)
+        }
+    }
+}
+
+/// The outputs of all the elements, in order
+fn construct(
+    elements: &[Box<Expr>],
+    input: &[Box<SchemeValue>],
+    env: &Env,
+) -> Result<Vec<Box<SchemeValue>>, QueryError> {
+    let mut values = vec![];
+    for element in elements {
+        values.extend(eval_expr(element, input, env)?);
     }
+    Ok(values)
 }
 
-const fn normalize_idx(index: i64, len: usize) -> usize {
-    (if index < 0 { len as i64 + index } else { index } as usize)
+/// The values of the template for this input. Unquoting a query with several
+/// outputs gives as many values, one with no output gives nothing.
+fn instantiate(
+    filter: &Filter,
+    template: &Template,
+    input: &[Box<SchemeValue>],
+    env: &Env,
+) -> QueryResult {
+    let elements = |templates: &[Template]| -> QueryResult {
+        let mut values = vec![];
+        for template in templates {
+            values.extend(instantiate(filter, template, input, env)?);
+        }
+        Ok(values)
+    };
+
+    Ok(match template {
+        Template::Value(value) => vec![value.clone()],
+        Template::Quote(template) => instantiate(filter, template, input, env)?
+            .into_iter()
+            .map(|value| Box::new(SchemeValue::Quote(value)))
+            .collect(),
+        Template::List(templates) => vec![Box::new(SchemeValue::List(elements(templates)?))],
+        Template::Dotted(templates, tail) => {
+            let values = elements(templates)?;
+            instantiate(filter, tail, input, env)?
+                .into_iter()
+                .map(|tail| Box::new(SchemeValue::dotted(values.clone(), tail)))
+                .collect()
+        }
+        Template::Vector(templates) => vec![Box::new(SchemeValue::Vector(elements(templates)?))],
+        Template::Unquote(query) => run_query(query, input.to_vec(), env)?,
+        Template::UnquoteSplicing(query) => {
+            let mut values = vec![];
+            for value in run_query(query, input.to_vec(), env)? {
+                match *value {
+                    SchemeValue::List(l) | SchemeValue::Vector(l) => values.extend(l),
+                    SchemeValue::Nil => {}
+                    _ => {
+                        return Err(QueryError::WrongType {
+                            filter: filter.clone(),
+                            value,
+                            expected: "a list or a vector",
+                        })
+                    }
+                }
+            }
+            values
+        }
+    })
+}
+
+/// Applies the operator once its left side didn't decide the result. Numbers
+/// are compared by value, `1 == 1.0`. Like Scheme's `<` and `string<?`, only
+/// numbers, strings, symbols and chars are ordered, and only against values of
+/// the same type.
+fn binary_op(
+    filter: &Filter,
+    op: BinaryOp,
+    left: &SchemeValue,
+    right: &SchemeValue,
+    input: &SchemeValue,
+) -> Result<SchemeValue, QueryError> {
+    let ordering = || match (left, right) {
+        (
+            SchemeValue::Int(_) | SchemeValue::Float(_),
+            SchemeValue::Int(_) | SchemeValue::Float(_),
+        ) => Ok(left.partial_cmp(right)),
+        (SchemeValue::String(a), SchemeValue::String(b))
+        | (SchemeValue::Symbol(a), SchemeValue::Symbol(b)) => Ok(Some(a.cmp(b))),
+        (SchemeValue::Char(a), SchemeValue::Char(b)) => Ok(Some(a.cmp(b))),
+        _ => Err(QueryError::WrongType {
+            filter: filter.clone(),
+            value: Box::new(input.clone()),
+            expected: "two numbers, strings, symbols or chars",
+        }),
+    };
+
+    let equal = left == right || left.partial_cmp(right) == Some(std::cmp::Ordering::Equal);
+    Ok(SchemeValue::Bool(match op {
+        BinaryOp::Equal => equal,
+        BinaryOp::NotEqual => !equal,
+        // NaN isn't less or greater than any number
+        BinaryOp::Less => ordering()?.is_some_and(|o| o.is_lt()),
+        BinaryOp::LessEqual => ordering()?.is_some_and(|o| o.is_le()),
+        BinaryOp::Greater => ordering()?.is_some_and(|o| o.is_gt()),
+        BinaryOp::GreaterEqual => ordering()?.is_some_and(|o| o.is_ge()),
+        BinaryOp::And | BinaryOp::Or => right.is_truthy(),
+        BinaryOp::Add
+        | BinaryOp::Subtract
+        | BinaryOp::Multiply
+        | BinaryOp::Divide
+        | BinaryOp::Remainder => return arithmetic(filter, op, left, right, input),
+    }))
+}
+
+/// Integer operations fail with `QueryError::Overflow` instead of wrapping, and
+/// a division that isn't exact returns a float where Scheme would return a
+/// rational. A float on either side makes the result a float
+fn arithmetic(
+    filter: &Filter,
+    op: BinaryOp,
+    left: &SchemeValue,
+    right: &SchemeValue,
+    input: &SchemeValue,
+) -> Result<SchemeValue, QueryError> {
+    let division_by_zero = || QueryError::DivisionByZero {
+        filter: filter.clone(),
+        value: Box::new(input.clone()),
+    };
+
+    match (left, right) {
+        (SchemeValue::Int(a), SchemeValue::Int(b)) => {
+            let (a, b) = (*a, *b);
+            if b == 0 && matches!(op, BinaryOp::Divide | BinaryOp::Remainder) {
+                return Err(division_by_zero());
+            }
+            let result = match op {
+                BinaryOp::Add => a.checked_add(b),
+                BinaryOp::Subtract => a.checked_sub(b),
+                BinaryOp::Multiply => a.checked_mul(b),
+                BinaryOp::Divide if a.checked_rem(b).is_some_and(|rem| rem != 0) => {
+                    return Ok(SchemeValue::Float(a as f64 / b as f64))
+                }
+                BinaryOp::Divide => a.checked_div(b),
+                // Only `i64::MIN % -1` overflows, and it's 0
+                _ => Some(a.wrapping_rem(b)),
+            };
+            result
+                .map(SchemeValue::Int)
+                .ok_or_else(|| QueryError::Overflow {
+                    filter: filter.clone(),
+                    value: Box::new(input.clone()),
+                })
+        }
+        (
+            SchemeValue::Int(_) | SchemeValue::Float(_),
+            SchemeValue::Int(_) | SchemeValue::Float(_),
+        ) => {
+            let (a, b) = (as_float(left), as_float(right));
+            if b == 0.0 && matches!(op, BinaryOp::Divide | BinaryOp::Remainder) {
+                return Err(division_by_zero());
+            }
+            Ok(SchemeValue::Float(match op {
+                BinaryOp::Add => a + b,
+                BinaryOp::Subtract => a - b,
+                BinaryOp::Multiply => a * b,
+                BinaryOp::Divide => a / b,
+                _ => a % b,
+            }))
+        }
+        (SchemeValue::Int(_) | SchemeValue::Float(_), value) | (value, _) => {
+            Err(QueryError::WrongType {
+                filter: filter.clone(),
+                value: Box::new(value.clone()),
+                expected: "a number",
+            })
+        }
+    }
+}
+
+fn as_float(value: &SchemeValue) -> f64 {
+    match value {
+        SchemeValue::Int(i) => *i as f64,
+        SchemeValue::Float(f) => *f,
+        _ => unreachable!("only called on numbers"),
+    }
+}
+
+fn eval_expr(expr: &Expr, branches: &[Box<SchemeValue>], env: &Env) -> QueryResult {
+    match expr {
+        Expr::Filter(f) => handle_filter(f, branches, env),
+        Expr::Value(v) => Ok(branches.iter().map(|_| v.clone()).collect()),
+        Expr::Variable(name) => {
+            let value = variable(name, env)?;
+            Ok(branches.iter().map(|_| Box::new(value.clone())).collect())
+        }
+    }
+}
+
+fn variable<'a>(name: &str, env: &'a Env) -> Result<&'a SchemeValue, QueryError> {
+    env.variable(name)
+        .ok_or_else(|| QueryError::UnknownVariable {
+            filter: Filter::Expr(Box::new(Expr::Variable(name.to_owned()))),
+            name: name.to_owned(),
+        })
+}
+
+/// Calls the function or the parameter bound in `scope`
+fn call_user(
+    filter: &Filter,
+    scope: &Rc<Scope>,
+    args: &[Box<Expr>],
+    branches: &[Box<SchemeValue>],
+    env: &Env,
+) -> QueryResult {
+    if args.len() != scope.arity() {
+        return Err(QueryError::WrongArity {
+            filter: filter.clone(),
+            expected: Arity::Exactly(scope.arity()),
+            got: args.len(),
+        });
+    }
+
+    match &scope.binding {
+        Binding::Param(expr, caller) => eval_expr(expr, branches, caller),
+        Binding::Def(def) => {
+            let def_env = Env {
+                funcs: env.funcs.clone(),
+                scope: Some(scope.clone()),
+            };
+            let body_env = def
+                .params()
+                .iter()
+                .zip(args)
+                .fold(def_env, |body_env, (param, arg)| {
+                    body_env.bind(
+                        param.clone(),
+                        Binding::Param(filter_arg(arg, env), env.clone()),
+                    )
+                });
+            run_query(def.body(), branches.to_vec(), &body_env)
+        }
+        Binding::Module(_) | Binding::Var(_) => {
+            unreachable!("`lookup` only returns functions and parameters")
+        }
+    }
+}
+
+/// A symbol naming a function or a parameter in scope is passed as a
+/// reference to it rather than as a symbol
+fn filter_arg(arg: &Expr, env: &Env) -> Box<Expr> {
+    match arg {
+        Expr::Value(value) => match value.as_ref() {
+            SchemeValue::Symbol(name) if env.lookup(name).is_some() || env.funcs.contains(name) => {
+                Box::new(Expr::Filter(Box::new(Filter::FuncCall {
+                    func: name.clone(),
+                    args: vec![],
+                })))
+            }
+            _ => Box::new(arg.clone()),
+        },
+        Expr::Filter(_) | Expr::Variable(_) => Box::new(arg.clone()),
+    }
+}
+
+/// Looks `key` up in a property list `(key value ...)`, or else in an
+/// association list of dotted pairs `((key . value) ...)`
+fn lookup_key(values: &[Box<SchemeValue>], key: &Key) -> Option<Box<SchemeValue>> {
+    values
+        .iter()
+        .skip_while(|k| !key.matches(k))
+        .nth(1)
+        .cloned()
+        .or_else(|| {
+            values.iter().find_map(|entry| match entry.as_ref() {
+                SchemeValue::Pair(l, _) if key.matches(&l[0]) => entry.cdr().map(Box::new),
+                _ => None,
+            })
+        })
+}
+
+/// Resolves a possibly negative index, `None` if it points before the start
+fn normalize_idx(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    usize::try_from(index).ok()
+}
+
+/// Like jq, out of range slice bounds are clamped to the list
+fn slice_bounds(start: Option<i64>, end: Option<i64>, len: usize) -> (usize, usize) {
+    let clamp = |i: i64| normalize_idx(i, len).unwrap_or(0).min(len);
+    let start = start.map_or(0, clamp);
+    let end = end.map_or(len, clamp);
+    (start, end.max(start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The outputs of the query on the input, separated by spaces
+    fn run(query: &str, input: &str) -> String {
+        let query = crate::compile(query).unwrap();
+        let input = crate::parse_scheme(input).unwrap();
+        handle_query(query, input)
+            .unwrap()
+            .iter()
+            .map(|value| value.to_string())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn defs_see_the_scope_they_are_defined_in() {
+        assert_eq!(run("def f: 1; def g: f; def f: 2; g", "()"), "1");
+        assert_eq!(run("def f: 1; ;(def f: 2; f), f", "()"), "2 1");
+        assert_eq!(run("def x: 1; def f(x): x; f 2", "()"), "2");
+    }
+
+    #[test]
+    fn defs_can_recurse() {
+        let count = "def count: if ; == () then 0 else cdr | count | ; + 1 end; count";
+        assert_eq!(run(count, "(a b c)"), "3");
+    }
+
+    #[test]
+    fn defs_shadow_builtins() {
+        assert_eq!(run("def length: 42; length", "(1 2)"), "42");
+    }
+
+    #[test]
+    fn filter_params_are_evaluated() {
+        assert_eq!(run("def f(g): =? g; f cr", "(1 2)"), "#t");
+        assert_eq!(run("def id: ;; def f(g): =? g; f id", "(1 2)"), "#t");
+        assert_eq!(run("def f(g): =? g; f ;(cdr)", "(1 2)"), "#f");
+    }
+
+    #[test]
+    fn function_params_are_called_by_builtins() {
+        let ten = "def ten: if ; == 1 then 10 else ; end;";
+        assert_eq!(
+            run(&format!("{} def w(g): walk g; w ten", ten), "(1 (2 3))"),
+            "(10 (2 3))"
+        );
+        assert_eq!(run("def w(g): walk g; w cr", "(1 (2 3))"), "(1 (2 3))");
+        assert_eq!(
+            run(
+                "def big: ;a > 1; def keep(g): ;() | filter g; keep big",
+                "((a 1) (a 2))"
+            ),
+            "(a 2)"
+        );
+    }
+
+    #[test]
+    fn jq_style_calls_are_rejected() {
+        assert!(crate::compile("def f(g): g; f(length)").is_err());
+        assert_eq!(run("def f(g): g; f (length)", "()"), "(length)");
+    }
+
+    fn eval(
+        left: SchemeValue,
+        op: BinaryOp,
+        right: SchemeValue,
+    ) -> Result<SchemeValue, QueryError> {
+        arithmetic(&Filter::Identity, op, &left, &right, &SchemeValue::Nil)
+    }
+
+    #[test]
+    fn integer_overflow() {
+        use SchemeValue::Int;
+        assert!(matches!(
+            eval(Int(i64::MAX), BinaryOp::Add, Int(1)),
+            Err(QueryError::Overflow { .. })
+        ));
+        assert!(matches!(
+            eval(Int(i64::MIN), BinaryOp::Divide, Int(-1)),
+            Err(QueryError::Overflow { .. })
+        ));
+        assert_eq!(
+            eval(Int(i64::MIN), BinaryOp::Remainder, Int(-1)).unwrap(),
+            Int(0)
+        );
+    }
+
+    #[test]
+    fn division() {
+        use SchemeValue::{Float, Int};
+        assert_eq!(eval(Int(7), BinaryOp::Divide, Int(2)).unwrap(), Float(3.5));
+        assert!(matches!(eval(Int(6), BinaryOp::Divide, Int(2)), Ok(Int(3))));
+        assert_eq!(eval(Int(7), BinaryOp::Remainder, Int(-3)).unwrap(), Int(1));
+        assert!(matches!(
+            eval(Int(1), BinaryOp::Divide, Int(0)),
+            Err(QueryError::DivisionByZero { .. })
+        ));
+        assert!(matches!(
+            eval(Int(1), BinaryOp::Remainder, Float(0.0)),
+            Err(QueryError::DivisionByZero { .. })
+        ));
+    }
+
+    #[test]
+    fn floats_are_contagious() {
+        use SchemeValue::{Float, Int};
+        assert!(matches!(eval(Int(1), BinaryOp::Add, Float(0.5)), Ok(Float(x)) if x == 1.5));
+        assert!(matches!(eval(Float(2.0), BinaryOp::Multiply, Int(3)), Ok(Float(x)) if x == 6.0));
+        assert!(matches!(
+            eval(Int(2), BinaryOp::Multiply, Int(3)),
+            Ok(Int(6))
+        ));
+    }
+
+    #[test]
+    fn non_numbers_are_rejected() {
+        assert!(matches!(
+            eval(SchemeValue::String("a".to_owned()), BinaryOp::Add, SchemeValue::Int(1)),
+            Err(QueryError::WrongType { value, .. }) if *value == SchemeValue::String("a".to_owned())
+        ));
+    }
+
+    #[test]
+    fn comma_binds_tighter_than_pipe() {
+        assert_eq!(run("1, 2 | ; + 1", "()"), "2 3");
+        assert_eq!(run(";(1, 2 | ; + 1), 0", "()"), "2 3 0");
+        assert_eq!(run(";(car, cdr) | length", "((1) 2 3)"), "1 2");
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(run("#t or #f and #f", "()"), "#t");
+        assert_eq!(run("#t or #t and #f", "()"), "#t");
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and_but_looser_than_comparisons() {
+        assert_eq!(run("not #f and #f", "()"), "#f");
+        assert_eq!(run("not 1 == 2", "()"), "#t");
+    }
+
+    #[test]
+    fn conditions_bind_tighter_than_comma_and_pipe() {
+        assert_eq!(run("1 == 1, 2", "()"), "#t 2");
+        assert_eq!(run("1 == 1 | 3", "()"), "3");
+        assert_eq!(run("#f or #t, #f", "()"), "#t #f");
+        assert_eq!(run("not #t, #t", "()"), "#f #t");
+    }
+
+    #[test]
+    fn and_or_short_circuit() {
+        assert_eq!(run("#f and car", "()"), "#f");
+        assert_eq!(run("#t or car", "()"), "#t");
+        let query = crate::compile("#t and car").unwrap();
+        assert!(handle_query(query, vec![Box::new(SchemeValue::Nil)]).is_err());
+    }
 }
@@ -1,25 +1,82 @@
 use core::fmt;
 
+use derive_new::new;
 use once_cell::sync::Lazy;
 use regex::Regex;
 
-#[derive(Debug, Clone, PartialEq)]
+use logos::Logos;
+
+use super::{doc::Doc, token::Token};
+
+/// How values are printed
+#[derive(Debug, Clone, new)]
+pub struct ReprOptions {
+    /// Print strings, chars and symbols without their syntax
+    raw: bool,
+    /// The maximum line width
+    width: usize,
+    /// Print booleans as `#true` and `#false`
+    long_bools: bool,
+}
+
+#[derive(Debug, Clone)]
 pub enum Value {
     Int(i64),
     Float(f64),
     String(String),
     Bool(bool),
     Symbol(String),
+    /// A keyword, its name is stored without the colons
+    Keyword(String, KeywordStyle),
     Quote(Box<Value>),
     Quasiquote(Box<Value>),
     Unquote(Box<Value>),
     UnquoteSplicing(Box<Value>),
     Char(char),
     List(Vec<Box<Value>>),
+    /// An improper list `(a b . c)`, the elements before the dot are never
+    /// empty and the tail is never a list
+    Pair(Vec<Box<Value>>, Box<Value>),
     Vector(Vec<Box<Value>>),
     Nil,
 }
 
+/// How a keyword is written, so it's printed back the same way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeywordStyle {
+    /// :name, as in Common Lisp and Clojure
+    Prefix,
+    /// #:name, as in Racket
+    Hash,
+    /// name:, as in Guile and DSSSL
+    Suffix,
+}
+
+/// `()` is the same list whether it's read as Nil or built as an empty list
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::String(a), Value::String(b)) | (Value::Symbol(a), Value::Symbol(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Keyword(a, a_style), Value::Keyword(b, b_style)) => {
+                a == b && a_style == b_style
+            }
+            (Value::Quote(a), Value::Quote(b))
+            | (Value::Quasiquote(a), Value::Quasiquote(b))
+            | (Value::Unquote(a), Value::Unquote(b))
+            | (Value::UnquoteSplicing(a), Value::UnquoteSplicing(b)) => a == b,
+            (Value::Char(a), Value::Char(b)) => a == b,
+            (Value::List(a), Value::List(b)) | (Value::Vector(a), Value::Vector(b)) => a == b,
+            (Value::Pair(a, a_tail), Value::Pair(b, b_tail)) => a == b && a_tail == b_tail,
+            (Value::List(l), Value::Nil) | (Value::Nil, Value::List(l)) => l.is_empty(),
+            (Value::Nil, Value::Nil) => true,
+            _ => false,
+        }
+    }
+}
+
 impl PartialOrd for Value {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         match (self, other) {
@@ -33,9 +90,49 @@ impl PartialOrd for Value {
 }
 
 impl Value {
+    /// Builds `(values . tail)`, flattening the tail when it's a list or
+    /// another pair so `(a . (b c))` is the same as `(a b c)`
+    pub fn dotted(mut values: Vec<Box<Value>>, tail: Box<Value>) -> Value {
+        match *tail {
+            Value::Nil => Value::List(values),
+            Value::List(l) => {
+                values.extend(l);
+                Value::List(values)
+            }
+            Value::Pair(l, tail) => {
+                values.extend(l);
+                Value::Pair(values, tail)
+            }
+            _ if values.is_empty() => *tail,
+            _ => Value::Pair(values, tail),
+        }
+    }
+
+    /// Like in Scheme, everything but `#f` is true
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Bool(false))
+    }
+
+    pub fn car(&self) -> Option<&Value> {
+        match self {
+            Value::List(l) | Value::Vector(l) | Value::Pair(l, _) => l.first().map(|v| &**v),
+            _ => None,
+        }
+    }
+
+    pub fn cdr(&self) -> Option<Value> {
+        match self {
+            Value::List(l) | Value::Vector(l) if !l.is_empty() => {
+                Some(Value::List(l[1..].to_vec()))
+            }
+            Value::Pair(l, tail) => Some(Value::dotted(l[1..].to_vec(), tail.clone())),
+            _ => None,
+        }
+    }
+
     pub fn is_iterable(&self) -> bool {
         match self {
-            Value::List(_) | Value::Vector(_) => true,
+            Value::List(_) | Value::Vector(_) | Value::Pair(..) => true,
             Value::Quote(v)
             | Value::Quasiquote(v)
             | Value::Unquote(v)
@@ -44,9 +141,13 @@ impl Value {
         }
     }
 
-    pub fn iter_values(&self) -> Option<impl Iterator<Item = &Value>> {
+    /// The elements of a list or vector, followed by the tail of a pair
+    pub fn iter_values(&self) -> Option<Box<dyn Iterator<Item = &Value> + '_>> {
         match self {
-            Value::List(l) | Value::Vector(l) => Some(l.iter().map(|v| &**v)),
+            Value::List(l) | Value::Vector(l) => Some(Box::new(l.iter().map(|v| &**v))),
+            Value::Pair(l, tail) => Some(Box::new(
+                l.iter().map(|v| &**v).chain(std::iter::once(&**tail)),
+            )),
             Value::Quote(v)
             | Value::Quasiquote(v)
             | Value::Unquote(v)
@@ -55,78 +156,455 @@ impl Value {
         }
     }
 
-    pub fn compact_repr(&self, raw: bool) -> String {
-        self.to_string()
+    /// The values directly inside this one: the elements of a list or vector,
+    /// followed by the tail of a pair, or the quoted value
+    pub fn children(&self) -> Vec<&Value> {
+        match self {
+            Value::List(l) | Value::Vector(l) => l.iter().map(|v| &**v).collect(),
+            Value::Pair(l, tail) => l
+                .iter()
+                .map(|v| &**v)
+                .chain(std::iter::once(&**tail))
+                .collect(),
+            Value::Quote(v)
+            | Value::Quasiquote(v)
+            | Value::Unquote(v)
+            | Value::UnquoteSplicing(v) => vec![v],
+            _ => vec![],
+        }
+    }
+
+    /// The value followed by everything nested in it, in pre-order
+    pub fn recurse(&self) -> Vec<&Value> {
+        let mut values = vec![];
+        let mut stack = vec![self];
+        while let Some(value) = stack.pop() {
+            values.push(value);
+            stack.extend(value.children().into_iter().rev());
+        }
+        values
     }
 
-    pub fn data_repr(&self, raw: bool) -> String {
-        self.rec_repr_inner(0)
+    pub fn compact_repr(&self, options: &ReprOptions) -> String {
+        match self.raw_repr() {
+            Some(repr) if options.raw => repr,
+            _ => self.flat_repr(options),
+        }
     }
 
-    pub fn code_repr(&self, raw: bool) -> String {
-        todo!()
+    pub fn data_repr(&self, options: &ReprOptions) -> String {
+        match self.raw_repr() {
+            Some(repr) if options.raw => repr,
+            _ => self.data_doc(options).pretty(options.width),
+        }
     }
 
-    fn rec_repr_inner(&self, depth: usize) -> String {
-        let repr = if self.is_iterable() {
-            let mut lst_repr = String::from(if matches!(self, Value::List(_)) {
-                "("
-            } else {
-                "#("
-            });
-
-            // We can unwrap here because we know it's an iterable (checked above)
-            let l = self.iter_values().unwrap();
-            for (i, v) in l.enumerate() {
-                if v.is_iterable() {
-                    lst_repr.push_str(&v.rec_repr_inner(depth + 1).trim_start());
-                    lst_repr.push('\n');
-                    lst_repr.push_str(&" ".repeat(depth));
-                } else {
-                    lst_repr.push_str(&v.rec_repr_inner(depth + 1).trim_start());
-                }
-                lst_repr.push(' ');
-            }
-            lst_repr = lst_repr.trim_end().to_string();
-            lst_repr.push(')');
-            lst_repr
+    /// The value on a single line
+    fn flat_repr(&self, options: &ReprOptions) -> String {
+        if options.long_bools {
+            format!("{:#}", self)
         } else {
             self.to_string()
+        }
+    }
+
+    /// The unquoted content of a string, char or symbol, as printed with
+    /// `--raw`. Other values are printed as usual.
+    fn raw_repr(&self) -> Option<String> {
+        match self {
+            Value::String(s) | Value::Symbol(s) => Some(s.clone()),
+            Value::Char(c) => Some(c.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Lists are kept on one line when they fit, otherwise every element goes
+    /// on its own line, aligned after the opening paren. In property lists,
+    /// each key stays on the line of its value.
+    fn data_doc(&self, options: &ReprOptions) -> Doc {
+        match self {
+            Value::List(l) => Self::list_doc("(", l, options),
+            Value::Vector(l) => Self::list_doc("#(", l, options),
+            Value::Pair(l, tail) => Doc::text("(")
+                .concat(
+                    Doc::join(
+                        l.iter()
+                            .map(|v| v.data_doc(options))
+                            .chain([Doc::text(". ").concat(tail.data_doc(options))]),
+                        Doc::Line,
+                    )
+                    .align(),
+                )
+                .concat(Doc::text(")"))
+                .group(),
+            Value::Quote(v) => Doc::text("'").concat(v.data_doc(options)),
+            Value::Quasiquote(v) => Doc::text("`").concat(v.data_doc(options)),
+            Value::Unquote(v) => Doc::text(",").concat(v.data_doc(options)),
+            Value::UnquoteSplicing(v) => Doc::text(",@").concat(v.data_doc(options)),
+            _ => Doc::text(self.flat_repr(options)),
+        }
+    }
+
+    fn list_doc(open: &str, values: &[Box<Value>], options: &ReprOptions) -> Doc {
+        let items = if is_plist(values) {
+            values
+                .chunks(2)
+                .map(|pair| {
+                    Doc::text(format!("{} ", pair[0].flat_repr(options)))
+                        .concat(pair[1].data_doc(options))
+                })
+                .collect::<Vec<_>>()
+        } else {
+            values.iter().map(|v| v.data_doc(options)).collect()
         };
 
-        format!("{}{}", " ".repeat(depth), repr)
+        Doc::text(open)
+            .concat(Doc::join(items, Doc::Line).align())
+            .concat(Doc::text(")"))
+            .group()
+    }
+
+    pub fn code_repr(&self, options: &ReprOptions) -> String {
+        match self.raw_repr() {
+            Some(repr) if options.raw => repr,
+            _ => self.code_repr_inner(0, options),
+        }
+    }
+
+    /// Lays out the value as Lisp code starting at `column`. Lists that don't
+    /// fit in the width are broken the way Emacs indents Scheme: special forms
+    /// keep their distinguished arguments on the first line and indent their
+    /// body by 2, other calls align their arguments under the first one.
+    fn code_repr_inner(&self, column: usize, options: &ReprOptions) -> String {
+        let flat = self.flat_repr(options);
+        if column + flat.chars().count() <= options.width {
+            return flat;
+        }
+
+        match self {
+            Value::Quote(v) => format!("'{}", v.code_repr_inner(column + 1, options)),
+            Value::Quasiquote(v) => format!("`{}", v.code_repr_inner(column + 1, options)),
+            Value::Unquote(v) => format!(",{}", v.code_repr_inner(column + 1, options)),
+            Value::UnquoteSplicing(v) => format!(",@{}", v.code_repr_inner(column + 2, options)),
+            Value::Vector(l) => format!("#({})", Self::code_repr_aligned(l, column + 2, options)),
+            Value::Pair(l, tail) => format!(
+                "({}\n{}. {})",
+                Self::code_repr_aligned(l, column + 1, options),
+                " ".repeat(column + 1),
+                tail.code_repr_inner(column + 3, options)
+            ),
+            Value::List(l) => {
+                let (name, args) = match l.split_first() {
+                    Some((head, args)) => match head.as_ref() {
+                        Value::Symbol(name) if !args.is_empty() => (name, args),
+                        _ => {
+                            return format!("({})", Self::code_repr_aligned(l, column + 1, options))
+                        }
+                    },
+                    None => return flat,
+                };
+
+                let Some(distinguished) = special_form_args(name, args) else {
+                    let args_column = column + name.chars().count() + 2;
+                    return format!(
+                        "({} {})",
+                        name,
+                        Self::code_repr_aligned(args, args_column, options)
+                    );
+                };
+
+                let mut repr = format!("({}", name);
+                for arg in args.iter().take(distinguished) {
+                    repr.push(' ');
+                    let arg_column = match repr.rfind('\n') {
+                        Some(i) => repr[i + 1..].chars().count(),
+                        None => column + repr.chars().count(),
+                    };
+                    repr.push_str(&arg.code_repr_inner(arg_column, options));
+                }
+                for arg in args.iter().skip(distinguished) {
+                    repr.push('\n');
+                    repr.push_str(&" ".repeat(column + 2));
+                    repr.push_str(&arg.code_repr_inner(column + 2, options));
+                }
+                repr.push(')');
+                repr
+            }
+            _ => flat,
+        }
+    }
+
+    fn code_repr_aligned(values: &[Box<Value>], column: usize, options: &ReprOptions) -> String {
+        values
+            .iter()
+            .map(|v| v.code_repr_inner(column, options))
+            .collect::<Vec<_>>()
+            .join(&format!("\n{}", " ".repeat(column)))
+    }
+}
+
+/// A list of alternating symbol or keyword keys and values, like
+/// `(name "x" age 20)`
+fn is_plist(values: &[Box<Value>]) -> bool {
+    !values.is_empty()
+        && values.len().is_multiple_of(2)
+        && values
+            .iter()
+            .step_by(2)
+            .all(|key| matches!(key.as_ref(), Value::Symbol(_) | Value::Keyword(..)))
+}
+
+/// The number of arguments of a special form that stay on the line of the
+/// form's name, the other ones being its body. `None` for regular calls.
+fn special_form_args(name: &str, args: &[Box<Value>]) -> Option<usize> {
+    match name {
+        // named let: (let loop ((i 0)) ...)
+        "let" if matches!(args.first().map(|a| a.as_ref()), Some(Value::Symbol(_))) => Some(2),
+        "define" | "define-syntax" | "define-values" | "lambda" | "let" | "let*" | "letrec"
+        | "letrec*" | "let-values" | "let*-values" | "let-syntax" | "letrec-syntax" | "when"
+        | "unless" | "case" | "syntax-rules" | "parameterize" | "guard" => Some(1),
+        "do" => Some(2),
+        "begin" => Some(0),
+        _ => None,
+    }
+}
+
+/// Escapes a string so it can be read back between double quotes, or a
+/// symbol between bars
+fn escape_string(s: &str, delimiter: char) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            c if c == delimiter => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '\x07' => escaped.push_str("\\a"),
+            '\x08' => escaped.push_str("\\b"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:x};", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Whether the symbol is read back as itself without bars
+fn is_plain_symbol(s: &str) -> bool {
+    let mut lex = Token::lexer(s);
+    matches!(lex.next(), Some(Ok(Token::Ident(ident))) if ident == s) && lex.next().is_none()
+}
+
+fn char_name(c: char) -> Option<&'static str> {
+    match c {
+        '\x07' => Some("alarm"),
+        '\x08' => Some("backspace"),
+        '\x7f' => Some("delete"),
+        '\x1b' => Some("escape"),
+        '\n' => Some("newline"),
+        '\0' => Some("null"),
+        '\r' => Some("return"),
+        ' ' => Some("space"),
+        '\t' => Some("tab"),
+        _ => None,
     }
 }
 
+/// `{:#}` prints booleans in their long form, `#true` and `#false`
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // children are printed in the same form as their parent
+        let repr = |v: &Value| {
+            if f.alternate() {
+                format!("{:#}", v)
+            } else {
+                format!("{}", v)
+            }
+        };
+        let join = |l: &[Box<Value>]| l.iter().map(|v| repr(v)).collect::<Vec<_>>().join(" ");
+
         let to_string = match self {
             Value::Int(i) => format!("{}", i),
-            Value::Float(f) => format!("{}", f),
-            Value::String(s) => format!("\"{}\"", s),
-            Value::Bool(b) => format!("{}", b),
-            Value::Symbol(s) => format!("{}", s),
-            Value::Quote(v) => format!("'{}", v),
-            Value::Quasiquote(v) => format!("`{}", v),
-            Value::Unquote(v) => format!(",{}", v),
-            Value::UnquoteSplicing(v) => format!(",@{}", v),
-            Value::Char(c) => format!("#\\{}", c),
-            Value::List(l) => format!(
-                "({})",
-                l.iter()
-                    .map(|v| format!("{}", v))
-                    .collect::<Vec<_>>()
-                    .join(" ")
-            ),
-            Value::Vector(v) => format!(
-                "#({})",
-                v.iter()
-                    .map(|v| format!("{}", v))
-                    .collect::<Vec<_>>()
-                    .join(" ")
-            ),
-            Value::Nil => format!("()"),
+            // floats always have a fractional part so they are read back as floats
+            Value::Float(x) if x.fract() == 0.0 && x.is_finite() => format!("{:.1}", x),
+            Value::Float(x) => format!("{}", x),
+            Value::String(s) => format!("\"{}\"", escape_string(s, '"')),
+            Value::Bool(true) if f.alternate() => "#true".to_owned(),
+            Value::Bool(false) if f.alternate() => "#false".to_owned(),
+            Value::Bool(true) => "#t".to_owned(),
+            Value::Bool(false) => "#f".to_owned(),
+            Value::Symbol(s) if is_plain_symbol(s) => s.to_string(),
+            Value::Symbol(s) => format!("|{}|", escape_string(s, '|')),
+            Value::Keyword(name, KeywordStyle::Prefix) => format!(":{}", name),
+            Value::Keyword(name, KeywordStyle::Hash) => format!("#:{}", name),
+            Value::Keyword(name, KeywordStyle::Suffix) => format!("{}:", name),
+            Value::Quote(v) => format!("'{}", repr(v)),
+            Value::Quasiquote(v) => format!("`{}", repr(v)),
+            Value::Unquote(v) => format!(",{}", repr(v)),
+            Value::UnquoteSplicing(v) => format!(",@{}", repr(v)),
+            Value::Char(c) => match char_name(*c) {
+                Some(name) => format!("#\\{}", name),
+                None if c.is_control() => format!("#\\x{:x}", *c as u32),
+                None => format!("#\\{}", c),
+            },
+            Value::List(l) => format!("({})", join(l)),
+            Value::Pair(l, tail) => format!("({} . {})", join(l), repr(tail)),
+            Value::Vector(v) => format!("#({})", join(v)),
+            Value::Nil => "()".to_string(),
         };
         write!(f, "{}", to_string)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{scheme::lexer::Lexer, scheme_parser::SchemeParser};
+
+    fn leaf() -> impl Strategy<Value = Value> {
+        prop_oneof![
+            any::<i64>().prop_map(Value::Int),
+            any::<f64>()
+                .prop_filter("only finite floats can be read", |x| x.is_finite())
+                .prop_map(Value::Float),
+            any::<String>().prop_map(Value::String),
+            any::<bool>().prop_map(Value::Bool),
+            "[a-z][a-z0-9!?*<>=/+-]{0,8}".prop_map(Value::Symbol),
+            // printed between bars
+            any::<String>().prop_map(Value::Symbol),
+            (
+                "[a-z][a-z0-9!?*<>=/+-]{0,8}",
+                prop_oneof![
+                    Just(KeywordStyle::Prefix),
+                    Just(KeywordStyle::Hash),
+                    Just(KeywordStyle::Suffix)
+                ]
+            )
+                .prop_map(|(name, style)| Value::Keyword(name, style)),
+            any::<char>().prop_map(Value::Char),
+            Just(Value::Nil),
+        ]
+    }
+
+    fn value() -> impl Strategy<Value = Value> {
+        leaf().prop_recursive(4, 64, 6, |inner| {
+            let values = |min| prop::collection::vec(inner.clone().prop_map(Box::new), min..6);
+            prop_oneof![
+                inner.clone().prop_map(|v| Value::Quote(Box::new(v))),
+                inner.clone().prop_map(|v| Value::Quasiquote(Box::new(v))),
+                inner.clone().prop_map(|v| Value::Unquote(Box::new(v))),
+                inner
+                    .clone()
+                    .prop_map(|v| Value::UnquoteSplicing(Box::new(v))),
+                values(0).prop_map(Value::List),
+                values(0).prop_map(Value::Vector),
+                (values(1), inner.clone()).prop_map(|(l, tail)| Value::dotted(l, Box::new(tail))),
+            ]
+        })
+    }
+
+    fn parse(s: &str) -> Vec<Box<Value>> {
+        SchemeParser::new()
+            .parse(Lexer::new(s))
+            .unwrap_or_else(|err| panic!("could not parse {}: {:?}", s, err))
+    }
+
+    fn data_repr(source: &str, width: usize) -> String {
+        parse(source)[0].data_repr(&ReprOptions::new(false, width, false))
+    }
+
+    #[test]
+    fn data_repr_keeps_short_lists_on_one_line() {
+        assert_eq!(data_repr("(age 20)", 80), "(age 20)");
+        assert_eq!(
+            data_repr("(name \"x\" info (age 20))", 80),
+            "(name \"x\" info (age 20))"
+        );
+    }
+
+    #[test]
+    fn data_repr_aligns_property_lists() {
+        assert_eq!(
+            data_repr("(name \"x\" info (age 20 city \"Montreal\"))", 20),
+            "(name \"x\"\n info (age 20\n       city \"Montreal\"))"
+        );
+    }
+
+    #[test]
+    fn data_repr_aligns_nested_lists() {
+        assert_eq!(
+            data_repr("((a 1 bb (c 2 ddd (e 3 f \"long string here\"))))", 25),
+            "((a 1\n  bb (c 2\n      ddd (e 3\n           f \"long string here\"))))"
+        );
+        assert_eq!(
+            data_repr("(a (b (c (d 1 2 3))))", 16),
+            "(a (b (c (d\n          1\n          2\n          3))))"
+        );
+    }
+
+    fn code_repr(source: &str, width: usize) -> String {
+        parse(source)[0].code_repr(&ReprOptions::new(false, width, false))
+    }
+
+    #[test]
+    fn code_repr_indents_bodies_by_two() {
+        assert_eq!(
+            code_repr("(define (f x) (display x) (newline))", 20),
+            "(define (f x)\n  (display x)\n  (newline))"
+        );
+        assert_eq!(
+            code_repr("(lambda (x y) (+ x y) (* x y))", 20),
+            "(lambda (x y)\n  (+ x y)\n  (* x y))"
+        );
+        assert_eq!(
+            code_repr("(let loop ((i 0)) (when (< i 10) (loop (+ i 1))))", 25),
+            "(let loop ((i 0))\n  (when (< i 10)\n    (loop (+ i 1))))"
+        );
+    }
+
+    #[test]
+    fn code_repr_keeps_the_distinguished_args_of_do() {
+        assert_eq!(
+            code_repr("(do ((i 0 (+ i 1))) ((= i 5)) (display i) (newline))", 30),
+            "(do ((i 0 (+ i 1))) ((= i 5))\n  (display i)\n  (newline))"
+        );
+    }
+
+    #[test]
+    fn code_repr_aligns_calls_under_the_first_arg() {
+        assert_eq!(
+            code_repr(
+                "(if (> x 0) (display \"positive\") (display \"negative\"))",
+                30
+            ),
+            "(if (> x 0)\n    (display \"positive\")\n    (display \"negative\"))"
+        );
+        assert_eq!(
+            code_repr("(cond ((> x 0) 'pos) ((< x 0) 'neg) (else 'zero))", 20),
+            "(cond ((> x 0) 'pos)\n      ((< x 0) 'neg)\n      (else 'zero))"
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn display_round_trips(v in value()) {
+            prop_assert_eq!(parse(&v.to_string()), vec![Box::new(v)]);
+        }
+
+        #[test]
+        fn long_bools_round_trip(v in value()) {
+            prop_assert_eq!(parse(&format!("{:#}", v)), vec![Box::new(v)]);
+        }
+
+        #[test]
+        fn data_repr_round_trips(v in value()) {
+            let options = ReprOptions::new(false, 20, false);
+            prop_assert_eq!(parse(&v.data_repr(&options)), vec![Box::new(v)]);
+        }
+    }
+}
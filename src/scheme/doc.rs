@@ -0,0 +1,129 @@
+use std::rc::Rc;
+
+/// A document in the style of Wadler's "A prettier printer": a tree of text
+/// with optional line breaks that are laid out as spaces when their enclosing
+/// group fits on the line.
+#[derive(Debug, Clone)]
+pub enum Doc {
+    Nil,
+    Text(String),
+    /// A space in flat mode, a newline followed by the indentation otherwise
+    Line,
+    Concat(Rc<Doc>, Rc<Doc>),
+    /// Sets the indentation of the inner document to the current column
+    Align(Rc<Doc>),
+    /// Lays out the inner document flat if it fits, breaking every `Line`
+    /// directly inside of it otherwise
+    Group(Rc<Doc>),
+}
+
+impl Doc {
+    pub fn text(s: impl Into<String>) -> Doc {
+        Doc::Text(s.into())
+    }
+
+    pub fn concat(self, other: Doc) -> Doc {
+        Doc::Concat(Rc::new(self), Rc::new(other))
+    }
+
+    pub fn align(self) -> Doc {
+        Doc::Align(Rc::new(self))
+    }
+
+    pub fn group(self) -> Doc {
+        Doc::Group(Rc::new(self))
+    }
+
+    /// Joins the documents with `sep` between each of them
+    pub fn join(docs: impl IntoIterator<Item = Doc>, sep: Doc) -> Doc {
+        let mut docs = docs.into_iter();
+        let Some(first) = docs.next() else {
+            return Doc::Nil;
+        };
+        docs.fold(first, |acc, doc| acc.concat(sep.clone()).concat(doc))
+    }
+
+    pub fn pretty(&self, width: usize) -> String {
+        let mut out = String::new();
+        let mut column = 0;
+        let mut stack: Vec<(usize, Mode, &Doc)> = vec![(0, Mode::Break, self)];
+
+        while let Some((indent, mode, doc)) = stack.pop() {
+            match doc {
+                Doc::Nil => {}
+                Doc::Text(s) => {
+                    out.push_str(s);
+                    column += s.chars().count();
+                }
+                Doc::Line => match mode {
+                    Mode::Flat => {
+                        out.push(' ');
+                        column += 1;
+                    }
+                    Mode::Break => {
+                        out.push('\n');
+                        out.push_str(&" ".repeat(indent));
+                        column = indent;
+                    }
+                },
+                Doc::Concat(a, b) => {
+                    stack.push((indent, mode, b));
+                    stack.push((indent, mode, a));
+                }
+                Doc::Align(d) => stack.push((column, mode, d)),
+                Doc::Group(d) => {
+                    let mode = match mode {
+                        Mode::Flat => Mode::Flat,
+                        Mode::Break if fits(width as isize - column as isize, d, &stack) => {
+                            Mode::Flat
+                        }
+                        Mode::Break => Mode::Break,
+                    };
+                    stack.push((indent, mode, d));
+                }
+            }
+        }
+
+        out
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Flat,
+    Break,
+}
+
+/// Whether `doc` laid out flat, followed by what comes after it up to the
+/// next line break, fits in `remaining` columns
+fn fits(mut remaining: isize, doc: &Doc, rest: &[(usize, Mode, &Doc)]) -> bool {
+    let mut stack: Vec<(Mode, &Doc)> = vec![(Mode::Flat, doc)];
+    let mut rest = rest.iter().rev();
+
+    while remaining >= 0 {
+        let (mode, doc) = match stack.pop() {
+            Some(item) => item,
+            None => match rest.next() {
+                Some((_, mode, doc)) => (*mode, *doc),
+                None => return true,
+            },
+        };
+
+        match doc {
+            Doc::Nil => {}
+            Doc::Text(s) => remaining -= s.chars().count() as isize,
+            Doc::Line => match mode {
+                Mode::Flat => remaining -= 1,
+                Mode::Break => return true,
+            },
+            Doc::Concat(a, b) => {
+                stack.push((mode, b));
+                stack.push((mode, a));
+            }
+            Doc::Align(d) => stack.push((mode, d)),
+            Doc::Group(d) => stack.push((mode, d)),
+        }
+    }
+
+    false
+}
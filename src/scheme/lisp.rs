@@ -0,0 +1,251 @@
+use std::{cell::RefCell, rc::Rc};
+
+use logos::Logos;
+use rust_lisp::{
+    default_env,
+    interpreter::eval,
+    model::{Env, List, Symbol, Value as LispValue},
+    parser::parse,
+};
+
+use super::{ast::Value, token::Token};
+use crate::func::FuncError;
+
+thread_local! {
+    /// Defining the `c[ad]+r` functions is slow, so the base environment is
+    /// only built once. Every evaluation gets its own child of it, so the
+    /// definitions of one don't leak into the others.
+    static BASE_ENV: Rc<RefCell<Env>> = env().expect("the c[ad]+r definitions are valid");
+
+    /// The last `(lambda ...)` evaluated by the current query and the function
+    /// it evaluated to, so a lambda applied to every input is only evaluated
+    /// once. Evaluating a lambda has no side effect, reusing it can't be told
+    /// apart from evaluating it again.
+    static LAMBDA: RefCell<Option<(Value, LispValue)>> = const { RefCell::new(None) };
+}
+
+/// Forgets the lambda evaluated by the previous query
+pub fn clear_cache() {
+    LAMBDA.with(|lambda| lambda.borrow_mut().take());
+}
+
+/// Evaluates `expr` with rust_lisp. If it evaluates to a function, the
+/// function is applied to `value` followed by `args`.
+pub fn eval_on(expr: &Value, value: &Value, args: &[Box<Value>]) -> Result<Value, FuncError> {
+    let env = Rc::new(RefCell::new(Env::extend(BASE_ENV.with(Rc::clone))));
+    let cached = LAMBDA.with(|lambda| match lambda.borrow().as_ref() {
+        Some((cached, result)) if cached == expr => Some(result.clone()),
+        _ => None,
+    });
+    let result = match cached {
+        Some(result) => result,
+        None => {
+            let result = eval(env.clone(), &to_lisp(expr)?).map_err(|e| FuncError::Lisp(e.msg))?;
+            if is_lambda(expr) {
+                LAMBDA.with(|lambda| *lambda.borrow_mut() = Some((expr.clone(), result.clone())));
+            }
+            result
+        }
+    };
+
+    let result = match result {
+        LispValue::Lambda(_) | LispValue::NativeFunc(_) | LispValue::NativeClosure(_) => {
+            // The arguments are already values, they must not be evaluated
+            // again
+            let mut call = vec![result];
+            for arg in std::iter::once(value).chain(args.iter().map(|arg| arg.as_ref())) {
+                call.push(quoted("quote", to_lisp(arg)?));
+            }
+            let call = call.into_iter().collect();
+            eval(env, &LispValue::List(call)).map_err(|e| FuncError::Lisp(e.msg))?
+        }
+        result => result,
+    };
+
+    from_lisp(&result)
+}
+
+/// The default rust_lisp environment, with the `c[ad]+r` functions up to four
+/// levels deep like the builtins of the query language
+fn env() -> Result<Rc<RefCell<Env>>, FuncError> {
+    let mut defs = String::new();
+    for depth in 2..=4 {
+        for n in 0..1 << depth {
+            let ops: String = (0..depth)
+                .map(|bit| if n >> bit & 1 == 0 { 'a' } else { 'd' })
+                .collect();
+            let body = ops
+                .chars()
+                .rev()
+                .fold("x".to_owned(), |body, op| format!("(c{}r {})", op, body));
+            defs.push_str(&format!("(defun c{}r (x) {})\n", ops, body));
+        }
+    }
+
+    let env = Rc::new(RefCell::new(default_env()));
+    for def in parse(&defs) {
+        let def = def.map_err(|e| FuncError::Lisp(e.msg))?;
+        eval(env.clone(), &def).map_err(|e| FuncError::Lisp(e.msg))?;
+    }
+    Ok(env)
+}
+
+fn keyword(s: &str) -> Option<Value> {
+    let mut lex = Token::lexer(s);
+    match (lex.next(), lex.next()) {
+        (Some(Ok(Token::KeywordIdent((name, style)))), None) => Some(Value::Keyword(name, style)),
+        _ => None,
+    }
+}
+
+fn is_lambda(expr: &Value) -> bool {
+    matches!(expr, Value::List(l) if matches!(l.first().map(|v| v.as_ref()), Some(Value::Symbol(s)) if s == "lambda"))
+}
+
+fn quoted(name: &str, value: LispValue) -> LispValue {
+    LispValue::List(
+        [LispValue::Symbol(Symbol::from(name)), value]
+            .iter()
+            .collect(),
+    )
+}
+
+/// Converts a value to rust_lisp. Vectors become lists, characters become
+/// strings and keywords become symbols, dotted pairs can't be converted. A
+/// symbol spelled like a keyword, such as `|:k|`, comes back as a keyword.
+pub fn to_lisp(value: &Value) -> Result<LispValue, FuncError> {
+    let list = |values: &[Box<Value>]| {
+        values
+            .iter()
+            .map(|v| to_lisp(v))
+            .collect::<Result<List, _>>()
+            .map(LispValue::List)
+    };
+
+    Ok(match value {
+        Value::Int(i) => LispValue::Int(*i),
+        Value::Float(f) => LispValue::Float(*f),
+        Value::String(s) => LispValue::String(s.clone()),
+        Value::Bool(b) => LispValue::from(*b),
+        Value::Symbol(s) => LispValue::Symbol(Symbol(s.clone())),
+        Value::Keyword(..) => LispValue::Symbol(Symbol(value.to_string())),
+        Value::Char(c) => LispValue::String(c.to_string()),
+        Value::Quote(v) => quoted("quote", to_lisp(v)?),
+        Value::Quasiquote(v) => quoted("quasiquote", to_lisp(v)?),
+        Value::Unquote(v) => quoted("unquote", to_lisp(v)?),
+        Value::UnquoteSplicing(v) => quoted("unquote-splicing", to_lisp(v)?),
+        Value::List(l) | Value::Vector(l) => list(l)?,
+        Value::Nil => LispValue::NIL,
+        Value::Pair(..) => {
+            return Err(FuncError::Lisp(format!(
+                "the dotted pair {} can't be passed to Scheme code",
+                value
+            )))
+        }
+    })
+}
+
+/// Converts a value back from rust_lisp, `(quote x)` is read back as `'x` and
+/// symbols that read as keywords become keywords again
+pub fn from_lisp(value: &LispValue) -> Result<Value, FuncError> {
+    Ok(match value {
+        LispValue::True => Value::Bool(true),
+        LispValue::False => Value::Bool(false),
+        LispValue::Int(i) => Value::Int(*i),
+        LispValue::Float(f) => Value::Float(*f),
+        LispValue::String(s) => Value::String(s.clone()),
+        LispValue::Symbol(Symbol(s)) => keyword(s).unwrap_or_else(|| Value::Symbol(s.clone())),
+        LispValue::List(l) if *l == List::NIL => Value::Nil,
+        LispValue::List(l) => {
+            let values = l
+                .into_iter()
+                .map(|v| from_lisp(&v).map(Box::new))
+                .collect::<Result<Vec<_>, _>>()?;
+            match values.as_slice() {
+                [head, v] => match head.as_ref() {
+                    Value::Symbol(s) if s == "quote" => Value::Quote(v.clone()),
+                    Value::Symbol(s) if s == "quasiquote" => Value::Quasiquote(v.clone()),
+                    Value::Symbol(s) if s == "unquote" => Value::Unquote(v.clone()),
+                    Value::Symbol(s) if s == "unquote-splicing" => {
+                        Value::UnquoteSplicing(v.clone())
+                    }
+                    _ => Value::List(values),
+                },
+                _ => Value::List(values),
+            }
+        }
+        _ => {
+            return Err(FuncError::Lisp(format!(
+                "a {} can't be returned from Scheme code",
+                value.type_name()
+            )))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(source: &str) -> Value {
+        *crate::parse_scheme(source).unwrap().remove(0)
+    }
+
+    fn round_trip(source: &str) -> Value {
+        from_lisp(&to_lisp(&read(source)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn values_survive_the_round_trip() {
+        for source in [
+            "1",
+            "-2.5",
+            "\"a \\\"string\\\"\"",
+            "#t",
+            "#f",
+            "symbol",
+            "|with space|",
+            ":prefix",
+            "#:hash",
+            "suffix:",
+            "'quoted",
+            "`(a ,b ,@c)",
+            "(1 (2 :k) ())",
+            "()",
+        ] {
+            assert_eq!(round_trip(source), read(source), "{}", source);
+        }
+    }
+
+    #[test]
+    fn chars_and_vectors_come_back_as_strings_and_lists() {
+        assert_eq!(round_trip("#\\a"), read("\"a\""));
+        assert_eq!(round_trip("#(1 2)"), read("(1 2)"));
+    }
+
+    #[test]
+    fn pairs_are_rejected() {
+        assert!(to_lisp(&read("(1 . 2)")).is_err());
+    }
+
+    #[test]
+    fn functions_are_applied_to_the_value() {
+        let identity = read("(lambda (x) x)");
+        assert_eq!(
+            eval_on(&identity, &read("(:k)"), &[]).unwrap(),
+            read("(:k)")
+        );
+        let add = read("(lambda (x y) (+ x y))");
+        assert_eq!(
+            eval_on(&add, &read("1"), &[Box::new(read("2"))]).unwrap(),
+            read("3")
+        );
+    }
+
+    #[test]
+    fn definitions_dont_leak_between_evaluations() {
+        let nil = Value::Nil;
+        eval_on(&read("(define zz 5)"), &nil, &[]).unwrap();
+        assert!(eval_on(&read("zz"), &nil, &[]).is_err());
+    }
+}
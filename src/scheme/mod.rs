@@ -1,3 +1,6 @@
 pub mod ast;
+pub mod doc;
 pub mod token;
 pub mod lexer;
+pub mod lisp;
+pub mod pattern;
@@ -0,0 +1,158 @@
+use super::ast::Value;
+
+/// The values captured by a pattern, by name
+pub type Captures = Vec<(String, Value)>;
+
+/// Matches the value against a pattern, returning what it captured.
+///
+/// In a pattern, `_` matches anything and `_name` matches anything and
+/// captures it as `name`. `p ...` in a list or a vector matches zero or more
+/// elements matching `p`, the names captured by `p` are then bound to the
+/// list of everything they captured. Any other value matches itself.
+pub fn match_pattern(pattern: &Value, value: &Value) -> Option<Captures> {
+    let mut captures = vec![];
+    matches(pattern, value, &mut captures).then_some(captures)
+}
+
+fn matches(pattern: &Value, value: &Value, captures: &mut Captures) -> bool {
+    match (pattern, value) {
+        (Value::Symbol(s), _) if s.starts_with('_') => {
+            if s.len() > 1 {
+                captures.push((s[1..].to_owned(), value.clone()));
+            }
+            true
+        }
+        (Value::List(p), Value::List(v)) | (Value::Vector(p), Value::Vector(v)) => {
+            matches_all(p, v, captures)
+        }
+        (Value::List(p), Value::Nil) => matches_all(p, &[], captures),
+        (Value::Nil, Value::List(v)) => v.is_empty(),
+        // (p1 p2 . tail) matches a list or a pair with at least two elements,
+        // `tail` matching what comes after them
+        (Value::Pair(p, tail), Value::List(v) | Value::Pair(v, _)) if v.len() >= p.len() => {
+            let rest = match value {
+                Value::Pair(_, value_tail) => Value::dotted(v[p.len()..].to_vec(), value_tail.clone()),
+                _ => Value::List(v[p.len()..].to_vec()),
+            };
+            matches_all(p, &v[..p.len()], captures) && matches(tail, &rest, captures)
+        }
+        (Value::Quote(p), Value::Quote(v))
+        | (Value::Quasiquote(p), Value::Quasiquote(v))
+        | (Value::Unquote(p), Value::Unquote(v))
+        | (Value::UnquoteSplicing(p), Value::UnquoteSplicing(v)) => matches(p, v, captures),
+        _ => pattern == value,
+    }
+}
+
+fn matches_all(patterns: &[Box<Value>], values: &[Box<Value>], captures: &mut Captures) -> bool {
+    let ellipsis = patterns
+        .iter()
+        .position(|p| matches!(p.as_ref(), Value::Symbol(s) if s == "..."));
+    let Some(ellipsis) = ellipsis.filter(|i| *i > 0) else {
+        return patterns.len() == values.len()
+            && patterns
+                .iter()
+                .zip(values)
+                .all(|(p, v)| matches(p, v, captures));
+    };
+
+    let (before, repeated, after) = (
+        &patterns[..ellipsis - 1],
+        &patterns[ellipsis - 1],
+        &patterns[ellipsis + 1..],
+    );
+    if values.len() < before.len() + after.len() {
+        return false;
+    }
+    let middle = &values[before.len()..values.len() - after.len()];
+
+    if !matches_all(before, &values[..before.len()], captures)
+        || !matches_all(after, &values[values.len() - after.len()..], captures)
+    {
+        return false;
+    }
+
+    let mut repetitions = vec![];
+    for value in middle {
+        let mut repetition = vec![];
+        if !matches(repeated, value, &mut repetition) {
+            return false;
+        }
+        repetitions.push(repetition);
+    }
+
+    for name in capture_names(repeated) {
+        let values = repetitions
+            .iter()
+            .flat_map(|repetition| repetition.iter().filter(|(n, _)| *n == name))
+            .map(|(_, value)| Box::new(value.clone()))
+            .collect();
+        captures.push((name, Value::List(values)));
+    }
+    true
+}
+
+fn capture_names(pattern: &Value) -> Vec<String> {
+    match pattern {
+        Value::Symbol(s) if s.starts_with('_') && s.len() > 1 => vec![s[1..].to_owned()],
+        _ => pattern
+            .children()
+            .into_iter()
+            .flat_map(capture_names)
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Value {
+        *crate::parse_scheme(source).unwrap().remove(0)
+    }
+
+    fn captures(pattern: &str, value: &str) -> Option<Vec<(String, String)>> {
+        match_pattern(&parse(pattern), &parse(value)).map(|captures| {
+            captures
+                .into_iter()
+                .map(|(name, value)| (name, value.to_string()))
+                .collect()
+        })
+    }
+
+    fn capture(name: &str, value: &str) -> (String, String) {
+        (name.to_owned(), value.to_owned())
+    }
+
+    #[test]
+    fn wildcards_and_literals() {
+        assert_eq!(captures("(define _ _)", "(define x 1)"), Some(vec![]));
+        assert_eq!(captures("(define _ _)", "(set! x 1)"), None);
+        assert_eq!(captures("(define _ _)", "(define x)"), None);
+    }
+
+    #[test]
+    fn dotted_patterns() {
+        assert_eq!(
+            captures("(define (_name . _args) _ ...)", "(define (f x y) x)"),
+            Some(vec![capture("name", "f"), capture("args", "(x y)")])
+        );
+    }
+
+    #[test]
+    fn ellipses_capture_lists() {
+        assert_eq!(
+            captures("(let ((_var _val) ...) _body ...)", "(let ((a 1) (b 2)) a b)"),
+            Some(vec![
+                capture("var", "(a b)"),
+                capture("val", "(1 2)"),
+                capture("body", "(a b)"),
+            ])
+        );
+        assert_eq!(
+            captures("(_first _ ... _last)", "(1 2 3 4)"),
+            Some(vec![capture("first", "1"), capture("last", "4")])
+        );
+        assert_eq!(captures("(_x ... 3)", "(1 2)"), None);
+    }
+}
@@ -1,8 +1,32 @@
 use std::fmt;
 
-use logos::Logos;
+use logos::{FilterResult, Lexer, Logos};
+
+use super::ast::{KeywordStyle, Value};
+
+/// Why some input isn't a token
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum TokenError {
+    #[default]
+    Invalid,
+    InvalidEscape,
+    UnknownChar,
+    UnterminatedComment,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenError::Invalid => write!(f, "this token is not valid here"),
+            TokenError::InvalidEscape => write!(f, "this string has an invalid escape"),
+            TokenError::UnknownChar => write!(f, "this character name is unknown"),
+            TokenError::UnterminatedComment => write!(f, "this comment is never closed"),
+        }
+    }
+}
 
 #[derive(Logos, Debug, PartialEq, Clone)]
+#[logos(error = TokenError)]
 pub enum Token {
     #[token("(")]
     OpenParen,
@@ -13,25 +37,23 @@ pub enum Token {
     #[token(")")]
     CloseParen,
 
+    #[token(".")]
+    Dot,
+
     #[regex(r"[a-zA-Z_!\$%\*\/:<=>\?@^~#](:?[#a-zA-Z0-9_!\$%\*\+\-\.\/<=>\?@^~])*", |lex| lex.slice().to_string())]
-    #[regex(r"\|(\\\||[^\|])*\|", |lex| {
+    // peculiar identifiers: +, -, ... and ->string
+    #[regex(r"[+\-]|\.\.\.|[+\-][a-zA-Z_!\$%\*\/:<=>\?@^~#+\-][#a-zA-Z0-9_!\$%\*\+\-\.\/<=>\?@^~]*", |lex| lex.slice().to_string())]
+    #[regex(r"\|([^\\|]|\\(.|\n))*\|", |lex| {
         let s = &lex.slice()[1..];
-        s[..s.len() - 1].to_string()
+        unescape_string(&s[..s.len() - 1]).ok_or(TokenError::InvalidEscape)
     })]
     Ident(String),
 
-    #[regex(r"[a-zA-Z_!\$%\*\/<=>\?@^~#](:?[#a-zA-Z0-9_!\$%\*\+\-\.\/<=>\?@^~])*:", |lex| {
-        let s = &lex.slice()[..];
-        s[..s.len() - 1].to_string()
-    })]
-    #[regex(r"#:[a-zA-Z_!\$%\*\/:<=>\?@^~#][#a-zA-Z0-9_!\$%\*\+\-\.\/:<=>\?@^~]*", |lex| {
-        lex.slice()[2..].to_string()
-    })]
-    #[regex(r":[a-zA-Z_!\$%\*\/:<=>\?@^~#][#a-zA-Z0-9_!\$%\*\+\-\.\/:<=>\?@^~]*", |lex| {
-        lex.slice()[1..].to_string()
-    })]
+    #[regex(r"[a-zA-Z_!\$%\*\/<=>\?@^~#](:?[#a-zA-Z0-9_!\$%\*\+\-\.\/<=>\?@^~])*:", keyword)]
+    #[regex(r"#:[a-zA-Z_!\$%\*\/:<=>\?@^~#][#a-zA-Z0-9_!\$%\*\+\-\.\/:<=>\?@^~]*", keyword)]
+    #[regex(r":[a-zA-Z_!\$%\*\/:<=>\?@^~#][#a-zA-Z0-9_!\$%\*\+\-\.\/:<=>\?@^~]*", keyword)]
     // #[regex(r"['`]\|(\\\||[^\|])\|", |lex| lex.slice().to_string())]
-    KeywordIdent(String),
+    KeywordIdent((String, KeywordStyle)),
 
     #[token("()")]
     Nil,
@@ -42,25 +64,19 @@ pub enum Token {
     #[regex(r"[-+]?[0-9]+\.[0-9]+", |lex| lex.slice().parse::<f64>().unwrap())]
     Float(f64),
 
-    #[regex(r#""(\\"|[^"])*""#, |lex| {
+    #[regex(r#""([^"\\]|\\(.|\n))*""#, |lex| {
         let s = &lex.slice()[1..];
-        s[..s.len() - 1].to_string()
+        unescape_string(&s[..s.len() - 1]).ok_or(TokenError::InvalidEscape)
     })]
     String(String),
 
-    #[regex(r"#\\(.|newline|space|tab)", |lex| {
-        let s = lex.slice();
-        match s {
-            "newline" => '\n',
-            "space" => ' ',
-            "tab" => '\t',
-            _ => s.chars().next().unwrap()
-        }
-    })]
+    #[token("#\\", char_literal)]
     Char(char),
 
     #[token("#t", |_| true)]
     #[token("#f", |_| false)]
+    #[token("#true", |_| true)]
+    #[token("#false", |_| false)]
     Bool(bool),
 
     #[token("'")]
@@ -75,6 +91,11 @@ pub enum Token {
     #[token(",@")]
     UnquoteSplicing,
 
+    /// `#;`, comments out the datum following it
+    #[token("#;")]
+    DatumComment,
+
+    #[token("#|", block_comment)]
     #[regex(r"#![^\n]*", logos::skip, priority = 5)]
     #[regex(r";[^\n]*", logos::skip)]
     #[regex(r"[ \t\n\f]+", logos::skip)]
@@ -87,12 +108,16 @@ impl fmt::Display for Token {
             Token::OpenParen => "OpenParen<(>".to_owned(),
             Token::HashOpenParen => "HashOpenParen<#(>".to_owned(),
             Token::CloseParen => "CloseParen<)>".to_owned(),
+            Token::Dot => "Dot<.>".to_owned(),
             Token::Ident(s) => format!("Ident({})", s),
-            Token::KeywordIdent(s) => format!("Keyword({})", s),
+            Token::KeywordIdent((name, style)) => {
+                format!("Keyword({})", Value::Keyword(name.clone(), *style))
+            }
             Token::Quote => "Quote<'>".to_owned(),
             Token::Quasiquote => "Quasiquote<`>".to_owned(),
             Token::Unquote => "Comma<,>".to_owned(),
             Token::UnquoteSplicing => "CommaAt<,@>".to_owned(),
+            Token::DatumComment => "DatumComment<#;>".to_owned(),
             Token::Int(i) => format!("Int({})", i),
             Token::Float(f) => format!("Float({})", f),
             Token::String(s) => format!("String({})", s),
@@ -104,3 +129,206 @@ impl fmt::Display for Token {
         write!(f, "{}", to_string)
     }
 }
+
+/// The name and the style of `:name`, `#:name` or `name:`
+pub fn keyword<'s, T: Logos<'s, Source = str>>(lex: &mut Lexer<'s, T>) -> (String, KeywordStyle) {
+    let s = lex.slice();
+    if let Some(name) = s.strip_prefix("#:") {
+        (name.to_owned(), KeywordStyle::Hash)
+    } else if let Some(name) = s.strip_prefix(':') {
+        (name.to_owned(), KeywordStyle::Prefix)
+    } else {
+        (s[..s.len() - 1].to_owned(), KeywordStyle::Suffix)
+    }
+}
+
+/// Skips a `#| ... |#` block comment, which can be nested
+fn block_comment(lex: &mut Lexer<Token>) -> FilterResult<(), TokenError> {
+    let remainder = lex.remainder().as_bytes();
+    let mut depth = 1;
+    let mut i = 0;
+
+    while i + 1 < remainder.len() {
+        match &remainder[i..i + 2] {
+            b"#|" => {
+                depth += 1;
+                i += 2;
+            }
+            b"|#" => {
+                depth -= 1;
+                i += 2;
+                if depth == 0 {
+                    lex.bump(i);
+                    return FilterResult::Skip;
+                }
+            }
+            _ => i += 1,
+        }
+    }
+
+    lex.bump(remainder.len());
+    FilterResult::Error(TokenError::UnterminatedComment)
+}
+
+/// Decodes the R7RS escapes of the content of a string literal, `None` if
+/// one of them is invalid
+pub fn unescape_string(s: &str) -> Option<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next()? {
+            'a' => out.push('\x07'),
+            'b' => out.push('\x08'),
+            't' => out.push('\t'),
+            'n' => out.push('\n'),
+            'r' => out.push('\r'),
+            '"' => out.push('"'),
+            '\\' => out.push('\\'),
+            '|' => out.push('|'),
+            'x' | 'X' => {
+                let mut hex = String::new();
+                loop {
+                    match chars.next()? {
+                        ';' => break,
+                        c => hex.push(c),
+                    }
+                }
+                out.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+            }
+            // line continuation: \<spaces><newline><spaces> is removed
+            c if c == ' ' || c == '\t' || c == '\n' => {
+                let mut seen_newline = c == '\n';
+                while let Some(&c) = chars.peek() {
+                    match c {
+                        ' ' | '\t' => {}
+                        '\n' if !seen_newline => seen_newline = true,
+                        _ => break,
+                    }
+                    chars.next();
+                }
+                if !seen_newline {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+    }
+
+    Some(out)
+}
+
+/// Lexes what follows `#\` in a character literal: a single character, a
+/// name like `newline` or a hex code like `x41`
+pub fn char_literal<'s, T: Logos<'s, Source = str, Error = TokenError>>(
+    lex: &mut Lexer<'s, T>,
+) -> Result<char, TokenError> {
+    let remainder = lex.remainder();
+    let first = remainder.chars().next().ok_or(TokenError::UnknownChar)?;
+    let len = if first.is_ascii_alphabetic() {
+        remainder
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(remainder.len())
+    } else {
+        first.len_utf8()
+    };
+    lex.bump(len);
+    char_from_name(&remainder[..len]).ok_or(TokenError::UnknownChar)
+}
+
+/// The character named by what follows `#\` in a character literal
+pub fn char_from_name(name: &str) -> Option<char> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(c);
+    }
+
+    match name {
+        "alarm" => Some('\x07'),
+        "backspace" => Some('\x08'),
+        "delete" => Some('\x7f'),
+        "escape" => Some('\x1b'),
+        "newline" => Some('\n'),
+        "null" => Some('\0'),
+        "return" => Some('\r'),
+        "space" => Some(' '),
+        "tab" => Some('\t'),
+        _ => name
+            .strip_prefix('x')
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .and_then(char::from_u32),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(source: &str) -> Vec<Result<Token, TokenError>> {
+        Token::lexer(source).collect()
+    }
+
+    fn string(s: &str) -> Vec<Result<Token, TokenError>> {
+        vec![Ok(Token::String(s.to_owned()))]
+    }
+
+    fn char(c: char) -> Vec<Result<Token, TokenError>> {
+        vec![Ok(Token::Char(c))]
+    }
+
+    #[test]
+    fn hex_escapes() {
+        assert_eq!(lex(r#""\x41;""#), string("A"));
+        assert_eq!(lex(r#""a\x3bb;c""#), string("aλc"));
+        assert_eq!(lex(r"|\x41;b|"), vec![Ok(Token::Ident("Ab".to_owned()))]);
+    }
+
+    #[test]
+    fn line_continuations() {
+        assert_eq!(lex("\"a\\\n  b\""), string("ab"));
+        assert_eq!(lex("\"a\\  \n\tb\""), string("ab"));
+    }
+
+    #[test]
+    fn invalid_escapes() {
+        for source in [
+            r#""\q""#,
+            r#""\x41""#,
+            r#""\xzz;""#,
+            r#""\xd800;""#,
+            "\"a\\  b\"",
+            r"|\q|",
+        ] {
+            assert_eq!(
+                lex(source),
+                vec![Err(TokenError::InvalidEscape)],
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn char_literals() {
+        assert_eq!(lex(r"#\a"), char('a'));
+        assert_eq!(lex(r"#\("), char('('));
+        assert_eq!(lex(r"#\λ"), char('λ'));
+        assert_eq!(lex(r"#\x41"), char('A'));
+        assert_eq!(lex(r"#\x"), char('x'));
+        assert_eq!(lex(r"#\space"), char(' '));
+        assert_eq!(lex(r"#\newline"), char('\n'));
+        assert_eq!(lex(r"#\alarm"), char('\x07'));
+        assert_eq!(lex(r"#\null"), char('\0'));
+    }
+
+    #[test]
+    fn unknown_char_names() {
+        assert_eq!(lex(r"#\nope"), vec![Err(TokenError::UnknownChar)]);
+        assert_eq!(lex(r"#\xd800"), vec![Err(TokenError::UnknownChar)]);
+    }
+}
@@ -2,7 +2,13 @@ use std::fmt;
 
 use logos::Logos;
 
+use crate::scheme::{
+    ast::{KeywordStyle, Value},
+    token::{char_literal, keyword, unescape_string, TokenError},
+};
+
 #[derive(Logos, Debug, PartialEq, Clone)]
+#[logos(error = TokenError)]
 pub enum Token {
     #[token("(")]
     OpenParen,
@@ -25,22 +31,103 @@ pub enum Token {
     #[token(":")]
     Colon,
 
+    #[token("def")]
+    Def,
+
+    #[token("import")]
+    Import,
+
+    #[token("include")]
+    Include,
+
+    #[token("as")]
+    As,
+
+    #[token("...")]
+    Ellipsis,
+
+    #[token("..")]
+    Recurse,
+
+    #[token(".")]
+    Dot,
+
+    #[token("match")]
+    Match,
+
+    #[token("if")]
+    If,
+
+    #[token("then")]
+    Then,
+
+    #[token("elif")]
+    Elif,
+
+    #[token("else")]
+    Else,
+
+    #[token("end")]
+    End,
+
+    #[token("==")]
+    Equal,
+
+    #[token("!=")]
+    NotEqual,
+
+    #[token("<", priority = 3)]
+    Less,
+
+    #[token("<=")]
+    LessEqual,
+
+    #[token(">", priority = 3)]
+    Greater,
+
+    #[token(">=")]
+    GreaterEqual,
+
+    #[token("+", priority = 3)]
+    Plus,
+
+    #[token("-", priority = 3)]
+    Minus,
+
+    #[token("*", priority = 3)]
+    Star,
+
+    #[token("/", priority = 3)]
+    Slash,
+
+    #[token("%", priority = 3)]
+    Percent,
+
+    #[token("and")]
+    And,
+
+    #[token("or")]
+    Or,
+
+    #[token("not")]
+    Not,
+
+    #[regex(r"\$[a-zA-Z_][a-zA-Z0-9_\-\?!]*", |lex| lex.slice()[1..].to_string(), priority = 10)]
+    Variable(String),
+
     #[regex(r"[a-zA-Z_!\$%\*\/<=>\?@^~#](:?[#a-zA-Z0-9_!\$%\*\+\-\.\/<=>\?@^~])*", |lex| lex.slice().to_string())]
+    // a function of an imported module: module::func
+    #[regex(r"[a-zA-Z_!\$%\*\/<=>\?@^~#](:?[#a-zA-Z0-9_!\$%\*\+\-\.\/<=>\?@^~])*::[a-zA-Z_!\$%\*\/<=>\?@^~#](:?[#a-zA-Z0-9_!\$%\*\+\-\.\/<=>\?@^~])*", |lex| lex.slice().to_string())]
+    // peculiar identifiers: ... and ->string, + and - are operators
+    #[regex(r"\.\.\.|[+\-][a-zA-Z_!\$%\*\/:<=>\?@^~#+\-][#a-zA-Z0-9_!\$%\*\+\-\.\/<=>\?@^~]*", |lex| lex.slice().to_string())]
     // #[regex(r"['`]\|(\\\||[^\|])\|", |lex| lex.slice().to_string())]
     Ident(String),
 
-    #[regex(r"[a-zA-Z_!\$%\*\/<=>\?@^~#](:?[#a-zA-Z0-9_!\$%\*\+\-\.\/<=>\?@^~])*:", |lex| {
-        let s = &lex.slice()[..];
-        s[..s.len() - 1].to_string()
-    })]
-    #[regex(r"#:[a-zA-Z_!\$%\*\/:<=>\?@^~#][#a-zA-Z0-9_!\$%\*\+\-\.\/:<=>\?@^~]*", |lex| {
-        lex.slice()[2..].to_string()
-    })]
-    #[regex(r":[a-zA-Z_!\$%\*\/:<=>\?@^~#][#a-zA-Z0-9_!\$%\*\+\-\.\/:<=>\?@^~]*", |lex| {
-        lex.slice()[1..].to_string()
-    })]
+    #[regex(r"[a-zA-Z_!\$%\*\/<=>\?@^~#](:?[#a-zA-Z0-9_!\$%\*\+\-\.\/<=>\?@^~])*:", keyword)]
+    #[regex(r"#:[a-zA-Z_!\$%\*\/:<=>\?@^~#][#a-zA-Z0-9_!\$%\*\+\-\.\/:<=>\?@^~]*", keyword)]
+    #[regex(r":[a-zA-Z_!\$%\*\/:<=>\?@^~#][#a-zA-Z0-9_!\$%\*\+\-\.\/:<=>\?@^~]*", keyword)]
     // #[regex(r"['`]\|(\\\||[^\|])\|", |lex| lex.slice().to_string())]
-    KeywordIdent(String),
+    KeywordIdent((String, KeywordStyle)),
 
     #[regex(r";[a-zA-Z_!\$%\*\/:<=>\?@^~#](:?[#a-zA-Z0-9_!\$%\*\+\-\/<=>\?@^~])*", |lex| lex.slice()[1..].to_string())]
     #[regex(r";\|(\\\||[^\|])*\|", |lex| {
@@ -49,13 +136,29 @@ pub enum Token {
     })]
     KeyIdent(String),
 
+    // ;:key or ;#:key, a key looked up as a keyword
+    #[regex(r";#?:[a-zA-Z_!\$%\*\/:<=>\?@^~#][#a-zA-Z0-9_!\$%\*\+\-\/:<=>\?@^~]*", |lex| {
+        let s = &lex.slice()[1..];
+        s.strip_prefix('#').unwrap_or(s)[1..].to_string()
+    }, priority = 10)]
+    KeyKeyword(String),
+
 
     #[token(";")]
     Identity,
 
+    #[token(";[")]
+    KeyOpenBracket,
+
+    #[token(";(")]
+    KeyOpenParen,
+
     #[token(",")]
     Comma,
 
+    #[token(",@")]
+    UnquoteSplicing,
+
     #[token(";()")]
     ListIter,
 
@@ -68,25 +171,19 @@ pub enum Token {
     #[regex(r"[-+]?[0-9]+\.[0-9]+", |lex| lex.slice().parse::<f64>().unwrap())]
     Float(f64),
 
-    #[regex(r#""(\\"|[^"])*""#, |lex| {
+    #[regex(r#""([^"\\]|\\(.|\n))*""#, |lex| {
         let s = &lex.slice()[1..];
-        s[..s.len() - 1].to_string()
+        unescape_string(&s[..s.len() - 1]).ok_or(TokenError::InvalidEscape)
     })]
     String(String),
 
-    #[regex(r"#\\(.|newline|space|tab)", |lex| {
-        let s = lex.slice();
-        match s {
-            "newline" => '\n',
-            "space" => ' ',
-            "tab" => '\t',
-            _ => s.chars().next().unwrap()
-        }
-    })]
+    #[token("#\\", char_literal)]
     Char(char),
 
     #[token("#t", |_| true)]
     #[token("#f", |_| false)]
+    #[token("#true", |_| true)]
+    #[token("#false", |_| false)]
     Bool(bool),
 
     #[token("'")]
@@ -110,10 +207,44 @@ impl fmt::Display for Token {
             Token::CloseBracket => "CloseBracket<]>".to_owned(),
             Token::Pipe => "Pipe<|>".to_owned(),
             Token::Ident(s) => format!("Ident({})", s),
-            Token::KeywordIdent(s) => format!("Keyword({})", s),
+            Token::KeywordIdent((name, style)) => {
+                format!("Keyword({})", Value::Keyword(name.clone(), *style))
+            }
             Token::KeyIdent(s) => format!("KeyIdent({})", s),
+            Token::KeyKeyword(s) => format!("KeyKeyword({})", s),
             Token::Identity => "Identity<;>".to_owned(),
+            Token::KeyOpenBracket => "KeyOpenBracket<;[>".to_owned(),
+            Token::KeyOpenParen => "KeyOpenParen<;(>".to_owned(),
+            Token::Def => "Def<def>".to_owned(),
+            Token::Import => "Import<import>".to_owned(),
+            Token::Include => "Include<include>".to_owned(),
+            Token::As => "As<as>".to_owned(),
+            Token::Ellipsis => "Ellipsis<...>".to_owned(),
+            Token::Recurse => "Recurse<..>".to_owned(),
+            Token::Dot => "Dot<.>".to_owned(),
+            Token::Match => "Match<match>".to_owned(),
+            Token::If => "If<if>".to_owned(),
+            Token::Then => "Then<then>".to_owned(),
+            Token::Elif => "Elif<elif>".to_owned(),
+            Token::Else => "Else<else>".to_owned(),
+            Token::End => "End<end>".to_owned(),
+            Token::Equal => "Equal<==>".to_owned(),
+            Token::NotEqual => "NotEqual<!=>".to_owned(),
+            Token::Less => "Less<<>".to_owned(),
+            Token::LessEqual => "LessEqual<<=>".to_owned(),
+            Token::Greater => "Greater<>>".to_owned(),
+            Token::GreaterEqual => "GreaterEqual<>=>".to_owned(),
+            Token::Plus => "Plus<+>".to_owned(),
+            Token::Minus => "Minus<->".to_owned(),
+            Token::Star => "Star<*>".to_owned(),
+            Token::Slash => "Slash</>".to_owned(),
+            Token::Percent => "Percent<%>".to_owned(),
+            Token::And => "And<and>".to_owned(),
+            Token::Or => "Or<or>".to_owned(),
+            Token::Not => "Not<not>".to_owned(),
+            Token::Variable(s) => format!("Variable(${})", s),
             Token::Comma => "Comma<,>".to_owned(),
+            Token::UnquoteSplicing => "UnquoteSplicing<,@>".to_owned(),
             Token::Colon => "Colon<:>".to_owned(),
             Token::Quote => "Quote<'>".to_owned(),
             Token::Quasiquote => "Quasiquote<`>".to_owned(),